@@ -1,5 +1,18 @@
-CREATE TABLE data (
-    hash INTEGER,
-    key TEXT PRIMARY KEY,
-    value TEXT
-)
+-- a key may hold several values (append writes) and several versions of them (concurrent
+-- writes), so key is indexed rather than unique; timestamp is when the value was written,
+-- in milliseconds since the epoch, and clock the JSON vector clock of its version
+CREATE TABLE IF NOT EXISTS data (
+    hash TEXT,
+    key TEXT,
+    value TEXT,
+    timestamp INTEGER NOT NULL DEFAULT 0,
+    clock TEXT NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS replicas (
+    hash TEXT,
+    key TEXT,
+    value TEXT,
+    timestamp INTEGER NOT NULL DEFAULT 0,
+    clock TEXT NOT NULL DEFAULT '{}'
+);
@@ -1,14 +1,23 @@
 use super::*;
+use crate::gossip::gossip::*;
 use crate::hash::*;
 use actix_multipart::Multipart;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{http::StatusCode, web, App, HttpResponse, HttpServer, Responder};
 use circula_buffer::CircularBuffer;
-use data::Data;
+use data::{Data, InsertSummary, WriteMode};
+use envelope::Envelope;
 use futures::{StreamExt, TryStreamExt};
+use ingest::{CsvIngest, UploadOptions, UPLOAD_BATCH};
 use msg::Message;
+use node_state::host_of;
+use pending::PendingRequests;
+use query::{Query, QueryParams};
+use rand::seq::SliceRandom;
+use replies::{lookup_reply, write_reply};
 use std::sync::Arc;
 use std::{fs, io::Write};
 use tokio::sync::{mpsc, Mutex};
+use transport::{serve_tcp, tcp_addr, Reply, Request, PEER_TRANSPORT};
 
 // Represents a node in the Chord ring network
 #[derive(Debug, Clone)]
@@ -18,13 +27,16 @@ pub struct ChordRing {
     tx: mpsc::Sender<Message>,
     logs: Arc<Mutex<Vec<String>>>,
     last_used_index: Arc<Mutex<usize>>,
+    pending: PendingRequests,
+    // gossip view pulled from the members, the ChordRing itself is not a member
+    members: Membership,
 }
 
 // Trait defining the core functionality for ChordRing
 pub trait ChordRingInterface {
     fn new() -> Self;
     async fn run(&self) -> std::io::Result<(String, u16)>;
-    async fn handle_message(&self, msg: Message) -> impl Responder;
+    async fn handle_message(&self, msg: Message) -> HttpResponse;
 }
 
 impl ChordRingInterface for ChordRing {
@@ -34,47 +46,41 @@ impl ChordRingInterface for ChordRing {
         #[cfg(debug_assertions)]
         println!("Creating a new ChordRing");
 
+        let logs = Arc::new(Mutex::new(Vec::new()));
         let chord_ring = ChordRing {
             nodes: Arc::new(Mutex::new(CircularBuffer::new())),
-            size: 2_usize.pow(*M as u32),
+            size: 1_usize.checked_shl(*M as u32).unwrap_or(usize::MAX),
             tx,
-            logs: Arc::new(Mutex::new(Vec::new())),
+            logs: logs.clone(),
             last_used_index: Arc::new(Mutex::new(0)),
+            pending: PendingRequests::new(),
+            members: Membership::new(logs),
         };
 
-        // Spawn a new thread to check nodes every 30 seconds
+        // Follow the members' gossip: pull the view of a random live member every
+        // interval and keep the node list in line with it
         let chord_ring_clone = chord_ring.clone();
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30)); // Check every 30 seconds
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_millis(*GOSSIP_INTERVAL));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
             loop {
                 interval.tick().await;
 
-                // Get a copy of current nodes to check
-                let nodes_to_check = {
+                let mut candidates = chord_ring_clone.members.live_members().await;
+                if candidates.is_empty() {
                     let nodes = chord_ring_clone.nodes.lock().await;
-                    nodes.iter().cloned().collect::<Vec<String>>()
-                };
-
-                // Check each node
-                for node in nodes_to_check {
-                    let tx = chord_ring_clone.tx.clone();
-
-                    // Send CheckNode message through the channel
-                    if let Err(e) = tx
-                        .send(Message::CheckNode {
-                            node_id: node.clone(),
-                        })
-                        .await
-                    {
-                        log_message!(
-                            chord_ring_clone,
-                            "Failed to send CheckNode message for node {}: {}",
-                            node,
-                            e
-                        );
+                    candidates = nodes.iter().cloned().collect();
+                }
+                candidates.shuffle(&mut rand::thread_rng());
+
+                for node in candidates.iter() {
+                    if chord_ring_clone.members.sync_from(node).await {
+                        break;
                     }
                 }
+                chord_ring_clone.sync_nodes().await;
             }
         });
 
@@ -87,22 +93,17 @@ impl ChordRingInterface for ChordRing {
                         let mut nodes = chord_ring_clone.nodes.lock().await;
                         if nodes.contains(&node_id) {
                             let node_id_clone = node_id.clone();
-                            match send_post_request!(
-                                &format!("http://{}/msg", node_id),
-                                Message::Ping
-                            ) {
-                                Ok(_) => {}
-                                Err(_) => {
-                                    log_message!(
-                                        chord_ring_clone,
-                                        "Failed to send Ping message to node {}",
-                                        node_id
-                                    );
-                                    // Find position first, then use it to remove if found
-                                    let pos = nodes.iter().position(|n| n == &node_id_clone);
-                                    if let Some(index) = pos {
-                                        nodes.remove(index);
-                                    }
+                            if !chord_ring_clone.members.ping(&node_id).await {
+                                log_message!(
+                                    chord_ring_clone,
+                                    "Failed to send Ping message to node {}",
+                                    node_id
+                                );
+                                chord_ring_clone.members.suspect(&node_id).await;
+                                // Find position first, then use it to remove if found
+                                let pos = nodes.iter().position(|n| n == &node_id_clone);
+                                if let Some(index) = pos {
+                                    nodes.remove(index);
                                 }
                             }
                         } else {
@@ -123,8 +124,11 @@ impl ChordRingInterface for ChordRing {
                             "ResKnownNode message received from node {}",
                             node_id
                         );
+                        chord_ring_clone.members.discover(&node_id).await;
                         let mut nodes = chord_ring_clone.nodes.lock().await;
-                        nodes.push_back(node_id);
+                        if !nodes.contains(&node_id) {
+                            nodes.push_back(node_id);
+                        }
                     }
                     Message::Leave { node_id } => {
                         log_message!(
@@ -132,6 +136,7 @@ impl ChordRingInterface for ChordRing {
                             "Leave message received from node {}",
                             node_id
                         );
+                        chord_ring_clone.members.dead(&node_id).await;
                         let mut nodes = chord_ring_clone.nodes.lock().await;
 
                         let index_to_remove = nodes.iter().position(|n| n == &node_id);
@@ -139,18 +144,43 @@ impl ChordRingInterface for ChordRing {
                             nodes.remove(index);
                         }
                     }
-                    Message::LookupRes { key, hops, data } => {
+                    Message::LookupRes {
+                        id,
+                        key,
+                        hops,
+                        node,
+                        data,
+                        path,
+                    } => {
                         log_message!(
                             chord_ring_clone,
-                            "Lookup response received for key: {} ({} hops)",
+                            "Lookup response received for key: {} ({} hops via {})",
                             key,
-                            hops
+                            hops,
+                            path.join(" -> ")
                         );
                         log_message!(
                             chord_ring_clone,
                             "Data found: {:?}",
                             data.clone().unwrap_or_default()
                         );
+                        chord_ring_clone
+                            .pending
+                            .resolve(
+                                id,
+                                Message::LookupRes {
+                                    id,
+                                    key,
+                                    hops,
+                                    node,
+                                    data,
+                                    path,
+                                },
+                            )
+                            .await;
+                    }
+                    Message::PutAck { id, .. } | Message::DeleteAck { id, .. } => {
+                        chord_ring_clone.pending.resolve(id, msg).await;
                     }
                     _ => {}
                 }
@@ -160,18 +190,10 @@ impl ChordRingInterface for ChordRing {
         chord_ring
     }
 
-    async fn handle_message(&self, msg: Message) -> impl Responder {
-        let tx = self.tx.clone();
-        if let Err(err) = tx.send(msg).await {
-            return HttpResponse::InternalServerError().json(serde_json::json!(
-                Message::ErrorMessage {
-                    error: err.to_string(),
-                }
-            ));
-        }
-        HttpResponse::Ok().json(serde_json::json!(Message::Success {
-            message: "Message sent successfully".to_string(),
-        }))
+    // answered like a node's /msg, senders read a Message back
+    async fn handle_message(&self, msg: Message) -> HttpResponse {
+        let (status, reply) = enqueue(&self.tx, msg);
+        HttpResponse::build(StatusCode::from_u16(status).unwrap_or(StatusCode::OK)).json(reply)
     }
 
     async fn run(&self) -> std::io::Result<(String, u16)> {
@@ -187,25 +209,76 @@ impl ChordRingInterface for ChordRing {
             logs: self.logs.clone(),
             nodes: self.nodes.clone(),
             tx: Some(self.tx.clone()),
+            pending: self.pending.clone(),
+            members: self.members.clone(),
         };
 
         let chord_ring = self.clone();
 
+        // Nodes answer lookups started here over the binary transport as well
+        if PEER_TRANSPORT.is_tcp() {
+            let listener =
+                tokio::net::TcpListener::bind(tcp_addr(&format!("{}:{}", *IP, *PORT))).await?;
+            let tx = self.tx.clone();
+            tokio::spawn(serve_tcp(listener, move |request| {
+                let tx = tx.clone();
+                async move {
+                    match request {
+                        Request::Message { envelope, .. } => match envelope.check_version() {
+                            Err(e) => Reply::Message {
+                                status: e.status(),
+                                message: Message::ErrorMessage {
+                                    error: e.to_string(),
+                                },
+                            },
+                            Ok(()) => {
+                                let (status, message) = enqueue(&tx, envelope.message);
+                                Reply::Message { status, message }
+                            }
+                        },
+                        Request::Rpc { .. } => Reply::Message {
+                            status: 404,
+                            message: Message::ErrorMessage {
+                                error: "The ChordRing takes no rpc calls".to_string(),
+                            },
+                        },
+                    }
+                }
+            }));
+        }
+
         HttpServer::new(move || {
             let chord_ring = chord_ring.clone();
             App::new()
                 .app_data(web::Data::new(app_state.clone()))
                 .route("/", web::get().to(handle_index))
                 .route("/data", web::get().to(data))
+                .route("/members", web::get().to(members))
                 .route("/upload", web::post().to(handle_upload))
                 .route("/lookup", web::post().to(handle_lookup))
+                .route("/kv/{key}", web::get().to(handle_get_key))
+                .route("/kv/{key}", web::put().to(handle_put_key))
+                .route("/kv/{key}", web::delete().to(handle_delete_key))
                 .route(
                     "/msg",
-                    web::post().to(move |msg: web::Json<Message>| {
+                    web::post().to(move |body: web::Json<serde_json::Value>| {
                         let chord_ring = chord_ring.clone();
                         async move {
-                            chord_ring.handle_message(msg.into_inner()).await;
-                            HttpResponse::Ok().body("Message handled")
+                            match Envelope::from_json(body.into_inner()) {
+                                Ok(envelope) => chord_ring.handle_message(envelope.message).await,
+                                Err(e) => {
+                                    log_message!(chord_ring, "Rejected a message: {}", e);
+                                    HttpResponse::build(
+                                        StatusCode::from_u16(e.status())
+                                            .unwrap_or(StatusCode::BAD_REQUEST),
+                                    )
+                                    .json(
+                                        Message::ErrorMessage {
+                                            error: e.to_string(),
+                                        },
+                                    )
+                                }
+                            }
                         }
                     }),
                 )
@@ -214,19 +287,65 @@ impl ChordRingInterface for ChordRing {
         .run()
         .await?;
 
-        Ok((String::from(IP.clone()), *PORT))
+        Ok((IP.clone(), *PORT))
+    }
+}
+
+// Hand a message to the ChordRing's loop. One that can't be queued is answered with 503,
+// so the sender retries it rather than taking it as delivered.
+fn enqueue(tx: &mpsc::Sender<Message>, msg: Message) -> (u16, Message) {
+    match tx.try_send(msg) {
+        Ok(()) => (
+            200,
+            Message::Success {
+                message: "Message sent successfully".to_string(),
+            },
+        ),
+        Err(err) => (
+            503,
+            Message::ErrorMessage {
+                error: err.to_string(),
+            },
+        ),
     }
 }
 
 impl ChordRing {
+    // Drop the members gossip declared dead from the node list and add the ones it is missing
+    async fn sync_nodes(&self) {
+        let members = self.members.members().await;
+        let mut nodes = self.nodes.lock().await;
+        for member in members {
+            let position = nodes.iter().position(|n| *n == member.id);
+            match (member.state, position) {
+                (MemberState::Dead, Some(index)) => {
+                    nodes.remove(index);
+                }
+                (MemberState::Alive, None) => nodes.push_back(member.id),
+                _ => {}
+            }
+        }
+    }
+
     async fn handle_known_node_req(&self, node: String) {
         log_message!(self, "Handling known node request from node: {}", node);
 
+        // A listed node asking again restarted on the same address, it takes its old place
+        // and is listed again once it has rejoined
+        {
+            let mut nodes = self.nodes.lock().await;
+            let listed = nodes.iter().position(|n| *n == node);
+            if let Some(index) = listed {
+                log_message!(self, "Node {} is rejoining the ring", node);
+                nodes.remove(index);
+            }
+        }
+
         // Check if ring is full
         if self.nodes.lock().await.len() == self.size {
             log_message!(self, "Ring is full. Cannot add more nodes.");
 
-            match send_post_request!(&format!("http://{}/msg", node), Message::RingIsFull) {
+            match send_message!(&node, Message::RingIsFull) {
                 Ok(_) => {}
                 Err(_) => {
                     log_message!(self, "Failed to send RingIsFull message to node: {}", node);
@@ -235,19 +354,6 @@ impl ChordRing {
             return;
         }
 
-        // Check if node already exists in the ring
-        if self.nodes.lock().await.contains(&node) {
-            log_message!(self, "Node already exists in the ring");
-
-            match send_post_request!(&format!("http://{}/msg", node), Message::NodeExists) {
-                Ok(_) => {}
-                Err(_) => {
-                    log_message!(self, "Failed to send NodeExists message to node: {}", node);
-                }
-            }
-            return;
-        }
-
         let node_to_join = {
             let mut index = self.last_used_index.lock().await;
             let mut nodes = self.nodes.lock().await;
@@ -261,8 +367,8 @@ impl ChordRing {
             }
         };
 
-        match send_post_request!(
-            &format!("http://{}/msg", node),
+        match send_message!(
+            &node,
             Message::ResKnownNode {
                 node_id: node_to_join.clone()
             }
@@ -301,38 +407,131 @@ async fn handle_lookup(
         }
     };
 
-    let mut nodes = state.nodes.lock().await;
+    match Query::from_body(&lookup_req) {
+        Ok(query) => lookup_key(state, key, query).await,
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "error",
+            "message": e
+        })),
+    }
+}
 
-    if nodes.is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({
+async fn handle_get_key(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    params: web::Query<QueryParams>,
+) -> impl Responder {
+    match params.parse() {
+        Ok(query) => lookup_key(state, path.into_inner(), query).await,
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
             "status": "error",
-            "message": "No nodes available"
-        }));
+            "message": e
+        })),
     }
+}
 
-    // Get first node and rotate list
-    let node = match nodes.front() {
-        Some(n) => n.clone(),
-        None => {
-            return HttpResponse::InternalServerError().json(serde_json::json!({
+async fn handle_put_key(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    put_req: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let (data, mode) = match Data::from_put(&path.into_inner(), &put_req) {
+        Ok(put) => put,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
                 "status": "error",
-                "message": "Failed to get node from ring"
+                "message": e
             }));
         }
     };
-    nodes.rotate();
-
-    // Send lookup request to the node
-    match send_post_request!(
-        &format!("http://{}/msg", node),
-        Message::LookupReq {
-            key: key.clone(),
-            hops: 0
+
+    let key = data.key.clone();
+    match route_request(&state, "Put", &key, |id, reply_to| Message::Put {
+        id,
+        data,
+        mode,
+        hops: 0,
+        reply_to,
+    })
+    .await
+    {
+        Ok(res) => write_reply(&key, res),
+        Err(err) => err,
+    }
+}
+
+async fn handle_delete_key(state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let key = path.into_inner();
+    match route_request(&state, "Delete", &key, |id, reply_to| Message::Delete {
+        id,
+        key: key.clone(),
+        hops: 0,
+        reply_to,
+    })
+    .await
+    {
+        Ok(res) => write_reply(&key, res),
+        Err(err) => err,
+    }
+}
+
+// Route a lookup into the ring and wait for the matching LookupRes
+async fn lookup_key(state: web::Data<AppState>, key: String, query: Query) -> HttpResponse {
+    match route_request(&state, "Lookup", &key, |id, reply_to| Message::LookupReq {
+        id,
+        key: key.clone(),
+        hops: 0,
+        reply_to,
+        path: Vec::new(),
+        query: query.clone(),
+    })
+    .await
+    {
+        Ok(res) => lookup_reply(&key, &query, res),
+        Err(err) => err,
+    }
+}
+
+// Send a request to the next node in the ring and wait for the response correlated with it
+async fn route_request(
+    state: &web::Data<AppState>,
+    kind: &str,
+    key: &str,
+    build: impl FnOnce(u64, String) -> Message,
+) -> Result<Option<Message>, HttpResponse> {
+    let node = {
+        let mut nodes = state.nodes.lock().await;
+
+        if nodes.is_empty() {
+            return Err(HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "message": "No nodes available"
+            })));
         }
-    ) {
+
+        // Get first node and rotate list
+        let node = match nodes.front() {
+            Some(n) => n.clone(),
+            None => {
+                return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                    "status": "error",
+                    "message": "Failed to get node from ring"
+                })));
+            }
+        };
+        nodes.rotate();
+        node
+    };
+
+    let (id, rx) = state.pending.register().await;
+
+    // Send the request to the node
+    match send_message!(&node, build(id, format!("{}:{}", *IP, *PORT))) {
         Ok(_) => {}
         Err(_) => {
-            // Send CheckNode message through the channel when lookup fails
+            state.pending.cancel(id).await;
+
+            // Send CheckNode message through the channel when the request fails
             if let Some(tx) = state.tx.as_ref() {
                 if let Err(e) = tx
                     .send(Message::CheckNode {
@@ -342,117 +541,238 @@ async fn handle_lookup(
                 {
                     log_message!(
                         state,
-                        "Failed to send CheckNode message after lookup failure: {}",
+                        "Failed to send CheckNode message after {} failure: {}",
+                        kind.to_lowercase(),
                         e
                     );
                 }
             }
 
-            return HttpResponse::InternalServerError().json(serde_json::json!({
+            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
                 "status": "error",
-                "message": "Failed to send lookup request to node"
-            }));
+                "message": format!("Failed to send {} request to node", kind.to_lowercase())
+            })));
         }
     }
 
     log_message!(
         state,
-        "Lookup request for key '{}' sent to node {}",
+        "{} request for key '{}' sent to node {}",
+        kind,
         key,
         node
     );
 
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "success",
-        "message": format!("Lookup request for key '{}' sent to node {}", key, node),
-        "node": node
-    }))
+    let timeout = std::time::Duration::from_secs(*REQUEST_TIMEOUT);
+    let res = state.pending.wait(id, rx, timeout).await;
+    if res.is_none() {
+        log_message!(state, "{} request for key '{}' timed out", kind, key);
+    }
+    Ok(res)
 }
 
-async fn handle_upload(state: web::Data<AppState>, mut payload: Multipart) -> impl Responder {
+async fn handle_upload(
+    state: web::Data<AppState>,
+    options: web::Query<UploadOptions>,
+    mut payload: Multipart,
+) -> impl Responder {
     log_message!(state, "Started handling file upload");
 
-    // Collect CSV data
-    let mut csv_data = String::new();
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            csv_data.push_str(std::str::from_utf8(&data).unwrap());
-        }
-    }
-
-    log_message!(state, "Finished reading CSV data");
-
-    // Split into lines and remove header
-    let lines: Vec<String> = csv_data.lines().skip(1).map(|s| s.to_string()).collect();
-    log_message!(state, "Processed {} lines from CSV", lines.len());
-
-    // Get available nodes
-    let nodes = state.nodes.lock().await;
-    let node_count = nodes.len();
-
-    if node_count == 0 {
+    // Rows are partitioned by the ring as it was when the upload started, a node that
+    // has since lost part of its range forwards those rows to the new owner
+    let mut ring: Vec<(ChordId, String)> = state
+        .nodes
+        .lock()
+        .await
+        .iter()
+        .map(|node| (hash(node), node.clone()))
+        .collect();
+    ring.sort();
+    if ring.is_empty() {
         log_message!(state, "Error: No nodes available in the network");
         return HttpResponse::BadRequest().json(serde_json::json!({
             "status": "error",
             "message": "No nodes available in the network"
         }));
     }
-
-    log_message!(state, "Found {} available nodes", node_count);
-
-    // Convert CSV lines to Data objects
-    let data_objects: Vec<Data> = lines
-        .iter()
-        .map(|line| {
-            let mut parts = line.splitn(2, ',');
-            let key = parts.next().unwrap_or("").trim().to_string();
-            let value = parts.next().unwrap_or("").trim().to_string();
-            Data { key, value }
-        })
-        .collect();
-
-    log_message!(
-        state,
-        "Converted CSV data to {} Data objects",
-        data_objects.len()
-    );
-
-    let mut success_count = 0;
-    let mut error_count = 0;
-
-    // Send data to all nodes
-    for node in nodes.iter() {
-        match send_post_request!(&format!("http://{}/insert", node), data_objects.clone()) {
-            Ok(_) => {
-                success_count += 1;
+    log_message!(state, "Found {} available nodes", ring.len());
+
+    let mut rows = 0;
+    let mut failed_rows = 0;
+    let mut errors = Vec::new();
+    let mut summary = InsertSummary::default();
+    let mode = options.mode.unwrap_or(WriteMode::InsertOnly);
+
+    // Every file in the form is a CSV of its own, parsed as it streams in and handed
+    // to the nodes UPLOAD_BATCH rows at a time. A file that can't be read ends the upload,
+    // the batches sent before it stay stored and are reported along with the error.
+    let mut failure = None;
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                failure = Some(format!("Failed to read the upload: {}", e));
+                break;
             }
-            Err(_) => {
-                error_count += 1;
-                log_message!(state, "Failed to send data to node {}", node);
+        };
+        let mut ingest = CsvIngest::new(options.0.clone());
+        let mut batch = Vec::new();
+        loop {
+            let (parsed, done) = match field.next().await {
+                Some(Ok(chunk)) => (ingest.feed(&chunk), false),
+                Some(Err(e)) => (Err(format!("Failed to read the upload: {}", e)), true),
+                None => (ingest.finish(), true),
+            };
+            match parsed {
+                Ok(parsed) => batch.extend(parsed),
+                Err(e) => failure = Some(e),
+            }
+            // the rows read before a failure are complete, they are stored all the same
+            let done = done || failure.is_some();
+            if batch.len() >= UPLOAD_BATCH || (done && !batch.is_empty()) {
+                let batch = std::mem::take(&mut batch);
+                summary.merge(send_batch(&state, &ring, batch, mode).await);
+            }
+            if done {
+                break;
             }
         }
+
+        rows += ingest.rows;
+        failed_rows += ingest.failed;
+        errors.extend(ingest.errors);
+        if failure.is_some() {
+            break;
+        }
     }
 
-    log_message!(
-        state,
-        "Completed upload - {} successful nodes, {} failed nodes",
-        success_count,
-        error_count
+    let stored = summary.stored_rows();
+    let skipped = summary.skipped_rows();
+    let unstored = summary.failed_rows();
+    let totals = format!(
+        "{} rows ({} failed), {} stored on {} nodes ({} skipped, {} not stored)",
+        rows,
+        failed_rows,
+        stored,
+        summary.acks.len(),
+        skipped,
+        unstored
     );
+    let (mut response, status, message) = match &failure {
+        Some(error) => {
+            log_message!(state, "Upload failed: {} (before it: {})", error, totals);
+            (
+                HttpResponse::BadRequest(),
+                "error",
+                format!("{} (before it: {})", error, totals),
+            )
+        }
+        None => {
+            log_message!(state, "Completed upload - {}", totals);
+            let status = if unstored == 0 {
+                "success"
+            } else if stored == 0 {
+                "error"
+            } else {
+                "partial"
+            };
+            (HttpResponse::Ok(), status, totals)
+        }
+    };
 
-    HttpResponse::Ok().json(serde_json::json!({
-        "status": "success",
-        "message": format!("Data sent to {} nodes successfully ({} failed)", success_count, error_count)
+    response.json(serde_json::json!({
+        "status": status,
+        "message": message,
+        "mode": mode,
+        "rows": rows,
+        "failed_rows": failed_rows,
+        "errors": errors,
+        "stored": stored,
+        "skipped": skipped,
+        "nodes": summary.acks,
+        "failed": summary.failed
     }))
 }
 
+// Send every node of the ring view only the rows it is responsible for, all at once.
+// A node that can't be reached has its rows go to the next one, which forwards them;
+// one that was sent the rows but didn't answer may have stored them, so they aren't
+// sent again.
+async fn send_batch(
+    state: &web::Data<AppState>,
+    ring: &[(ChordId, String)],
+    batch: Vec<Data>,
+    mode: WriteMode,
+) -> InsertSummary {
+    let mut partitions: Vec<Vec<Data>> = vec![Vec::new(); ring.len()];
+    for item in batch {
+        let key_hash = hash(&item.key);
+        // the first node at or after the key, wrapping around to the start of the ring
+        let owner = ring.partition_point(|(node_hash, _)| *node_hash < key_hash) % ring.len();
+        partitions[owner].push(item);
+    }
+
+    let sends = partitions
+        .into_iter()
+        .enumerate()
+        .filter(|(_, rows)| !rows.is_empty())
+        .map(|(owner, rows)| async move {
+            // the owner and the nodes after it, as many as a node keeps successors, one
+            // per host since the virtual nodes of a host that is down are down with it
+            let mut candidates: Vec<&String> = Vec::new();
+            for (_, node) in ring.iter().cycle().skip(owner).take(ring.len()) {
+                if candidates.len() < *N && !candidates.iter().any(|c| host_of(c) == host_of(node))
+                {
+                    candidates.push(node);
+                }
+            }
+            for node in candidates.iter() {
+                let url = format!("http://{}/insert?mode={}", node, mode.as_str());
+                let client = &peer_client::PEER_CLIENT;
+                match send_post_request!(&url, rows, client.retry.max_retries, client.bulk_timeout)
+                {
+                    Ok(res) => {
+                        return match res.json::<InsertSummary>().await {
+                            Ok(summary) => summary,
+                            Err(e) => InsertSummary::failure(node, rows.len(), e.to_string()),
+                        };
+                    }
+                    Err(e) if e.is_connect() => {
+                        log_message!(state, "Failed to send data to node {}: {}", node, e)
+                    }
+                    Err(e) => {
+                        log_message!(state, "Node {} did not answer: {}", node, e);
+                        return InsertSummary::failure(
+                            node,
+                            rows.len(),
+                            format!("no answer, the rows may have been stored: {}", e),
+                        );
+                    }
+                }
+            }
+            InsertSummary::failure(
+                candidates[0],
+                rows.len(),
+                "neither it nor the nodes after it could be reached".to_string(),
+            )
+        });
+
+    let mut summary = InsertSummary::default();
+    for result in futures::future::join_all(sends).await {
+        summary.merge(result);
+    }
+    summary
+}
+
 // State shared across HTTP handlers
 #[derive(Clone)]
 struct AppState {
     logs: Arc<Mutex<Vec<String>>>,
     nodes: Arc<Mutex<CircularBuffer<String>>>,
     tx: Option<mpsc::Sender<Message>>, // Add this field
+    pending: PendingRequests,
+    members: Membership,
 }
 
 // Handler for the index route
@@ -462,6 +782,10 @@ async fn handle_index() -> impl Responder {
         .body(fs::read_to_string(HTML_PATH).expect("Unable to read template file"))
 }
 
+async fn members(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state.members.members().await)
+}
+
 async fn data(state: web::Data<AppState>) -> impl Responder {
     let nodes_lock = state.nodes.lock().await;
     let logs_lock = state.logs.lock().await;
@@ -474,11 +798,21 @@ async fn data(state: web::Data<AppState>) -> impl Responder {
         .map(|node| {
             serde_json::json!({
                 "id": node.to_string(),
+                "host": host_of(node),
                 "hash": hash(node)
             })
         })
         .collect::<Vec<_>>();
 
+    // Virtual nodes of one process share its host, so count the distinct ones
+    let mut hosts = nodes_lock
+        .iter()
+        .map(|node| host_of(node))
+        .collect::<Vec<_>>();
+    hosts.sort();
+    hosts.dedup();
+    let host_count = hosts.len();
+
     let logs = logs_lock
         .iter()
         .map(|log| log.to_string())
@@ -488,6 +822,7 @@ async fn data(state: web::Data<AppState>) -> impl Responder {
         "nodes": nodes,
         "logs": logs,
         "node_count": node_count,
+        "host_count": host_count,
         "log_count": log_count
     });
 
@@ -0,0 +1,391 @@
+use super::*;
+use csv_core::{ReadRecordResult, Reader};
+use data::{parse_timestamp, Data, NewData, WriteMode};
+
+// rows handed to the nodes at a time while the upload streams in
+pub const UPLOAD_BATCH: usize = 1000;
+// failed rows reported back with their error, the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+
+// Query parameters of POST /upload
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UploadOptions {
+    // header of the key column, the first column by default
+    pub key: Option<String>,
+    // comma separated headers of the value columns, every other column by default
+    pub values: Option<String>,
+    // rows to drop right after the header, like the "Ticker" row of a yfinance export
+    #[serde(default)]
+    pub skip_rows: usize,
+    // header of a column with the time each row was written (milliseconds, RFC 3339 or
+    // YYYY-MM-DD), for keep_newest; rows are stamped with the upload time otherwise
+    pub timestamp: Option<String>,
+    // what to do with keys that are already stored, fail their batch by default
+    pub mode: Option<WriteMode>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    // the header is row 1
+    pub row: u64,
+    pub error: String,
+}
+
+// Positions of the chosen columns, resolved from the header
+#[derive(Debug)]
+struct Columns {
+    key: usize,
+    timestamp: Option<usize>,
+    values: Vec<(String, usize)>,
+    width: usize,
+}
+
+// Turns an RFC 4180 CSV file into Data one chunk at a time, as it comes off the wire.
+// Chunks may end anywhere, in the middle of a quoted field or of a UTF-8 character;
+// a record is only looked at once it is complete.
+pub struct CsvIngest {
+    reader: Reader,
+    options: UploadOptions,
+    // the record being read, its fields back to back and where each one ends
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+    columns: Option<Columns>,
+    skip: usize,
+    row: u64,
+    pub rows: usize,
+    pub failed: usize,
+    pub errors: Vec<RowError>,
+}
+
+impl CsvIngest {
+    pub fn new(options: UploadOptions) -> Self {
+        CsvIngest {
+            reader: Reader::new(),
+            skip: options.skip_rows,
+            options,
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+            columns: None,
+            row: 0,
+            rows: 0,
+            failed: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    // The rows completed by this chunk. Fails only when the header doesn't have the
+    // requested columns, a bad row is recorded in `errors` and skipped.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Data>, String> {
+        let mut rows = Vec::new();
+        // an empty input tells the reader the file is over
+        if !chunk.is_empty() {
+            self.read(chunk, &mut rows)?;
+        }
+        Ok(rows)
+    }
+
+    // The end of the file, which also ends a last row without a line break
+    pub fn finish(&mut self) -> Result<Vec<Data>, String> {
+        let mut rows = Vec::new();
+        self.read(&[], &mut rows)?;
+        if self.columns.is_none() {
+            return Err("The file is empty, it needs at least a header".to_string());
+        }
+        Ok(rows)
+    }
+
+    fn read(&mut self, mut input: &[u8], rows: &mut Vec<Data>) -> Result<(), String> {
+        loop {
+            let (result, nin, nout, nend) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[nin..];
+            self.output_len += nout;
+            self.ends_len += nend;
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return Ok(()),
+                ReadRecordResult::OutputFull => {
+                    let len = self.output.len();
+                    self.output.resize(len * 2, 0);
+                }
+                ReadRecordResult::OutputEndsFull => {
+                    let len = self.ends.len();
+                    self.ends.resize(len * 2, 0);
+                }
+                ReadRecordResult::Record => {
+                    let fields = self.fields();
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                    if let Some(row) = self.record(fields)? {
+                        rows.push(row);
+                    }
+                }
+            }
+        }
+    }
+
+    // The fields of the record just read, or the index of the first one that isn't UTF-8.
+    // Spaces are part of a field in RFC 4180, so they are kept.
+    fn fields(&self) -> Result<Vec<String>, usize> {
+        let mut start = 0;
+        self.ends[..self.ends_len]
+            .iter()
+            .enumerate()
+            .map(|(i, &end)| {
+                let field = std::str::from_utf8(&self.output[start..end]).map_err(|_| i);
+                start = end;
+                field.map(str::to_string)
+            })
+            .collect()
+    }
+
+    fn record(&mut self, fields: Result<Vec<String>, usize>) -> Result<Option<Data>, String> {
+        // blank lines are no rows
+        if matches!(&fields, Ok(f) if f.len() == 1 && f[0].is_empty()) {
+            return Ok(None);
+        }
+        self.row += 1;
+        if self.columns.is_some() && self.skip > 0 {
+            self.skip -= 1;
+            return Ok(None);
+        }
+
+        let Some(columns) = &self.columns else {
+            let header =
+                fields.map_err(|i| format!("Column {} of the header is not valid UTF-8", i + 1))?;
+            // only the column names are trimmed, so "Close, Volume" still names both
+            let header: Vec<String> = header.iter().map(|h| h.trim().to_string()).collect();
+            self.columns = Some(self.resolve(&header)?);
+            return Ok(None);
+        };
+
+        let row = fields
+            .map_err(|i| format!("field {} is not valid UTF-8", i + 1))
+            .and_then(|fields| {
+                if fields.len() != columns.width {
+                    return Err(format!(
+                        "expected {} fields, found {}",
+                        columns.width,
+                        fields.len()
+                    ));
+                }
+                let key = fields[columns.key].clone();
+                if key.is_empty() {
+                    return Err("the key is empty".to_string());
+                }
+                // a single column is stored as it is, several as a JSON document
+                let value = match columns.values.as_slice() {
+                    [(_, index)] => fields[*index].clone(),
+                    values => serde_json::Value::Object(
+                        values
+                            .iter()
+                            .map(|(name, index)| (name.clone(), field_value(&fields[*index])))
+                            .collect(),
+                    )
+                    .to_string(),
+                };
+                let mut data = Data::new(&key, &value);
+                if let Some(index) = columns.timestamp {
+                    data.timestamp = parse_timestamp(&fields[index])
+                        .ok_or_else(|| format!("'{}' is not a timestamp", fields[index]))?;
+                }
+                Ok(data)
+            });
+
+        match row {
+            Ok(data) => {
+                self.rows += 1;
+                Ok(Some(data))
+            }
+            Err(error) => {
+                self.failed += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(RowError {
+                        row: self.row,
+                        error,
+                    });
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn resolve(&self, header: &[String]) -> Result<Columns, String> {
+        let position = |name: &str| {
+            header.iter().position(|h| h == name).ok_or_else(|| {
+                format!(
+                    "No column named '{}', the header has: {}",
+                    name,
+                    header.join(", ")
+                )
+            })
+        };
+
+        let key = match &self.options.key {
+            Some(name) => position(name)?,
+            None => 0,
+        };
+        let timestamp = match &self.options.timestamp {
+            Some(name) => Some(position(name)?),
+            None => None,
+        };
+        let values = match &self.options.values {
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Ok((name.to_string(), position(name)?)))
+                .collect::<Result<Vec<_>, String>>()?,
+            None => header
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != key)
+                .map(|(i, name)| (name.clone(), i))
+                .collect(),
+        };
+        if values.is_empty() {
+            return Err("No value columns, the file needs one besides the key".to_string());
+        }
+
+        Ok(Columns {
+            key,
+            timestamp,
+            values,
+            width: header.len(),
+        })
+    }
+}
+
+// Fields written as JSON numbers go into documents as numbers, so lookups can filter on
+// them numerically. Anything else, like "336M" or a zip code with a leading 0, stays text,
+// and so does a number that wouldn't be written back the same: an id too long for a u64,
+// " 1 " with its spaces, 1e3.
+fn field_value(field: &str) -> serde_json::Value {
+    match serde_json::from_str::<serde_json::Number>(field) {
+        Ok(number) if number.to_string() == field => serde_json::Value::Number(number),
+        _ => serde_json::Value::from(field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(key: Option<&str>, skip_rows: usize) -> UploadOptions {
+        UploadOptions {
+            key: key.map(str::to_string),
+            skip_rows,
+            ..Default::default()
+        }
+    }
+
+    // the rows of a file fed in the given chunks
+    fn ingest(options: UploadOptions, chunks: &[&[u8]]) -> (CsvIngest, Vec<(String, String)>) {
+        let mut ingest = CsvIngest::new(options);
+        let mut rows = Vec::new();
+        for chunk in chunks {
+            rows.extend(ingest.feed(chunk).unwrap());
+        }
+        rows.extend(ingest.finish().unwrap());
+        let rows = rows.into_iter().map(|d| (d.key, d.value)).collect();
+        (ingest, rows)
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn chunks_may_split_quoted_fields_and_characters() {
+        let file = "name,city\n\"Doe, Jane\",\"Zürich\"\r\nRenée,\"São\nPaulo\"\n".as_bytes();
+        let expected = vec![pair("Doe, Jane", "Zürich"), pair("Renée", "São\nPaulo")];
+        for at in 0..=file.len() {
+            let (ingest, rows) = ingest(options(None, 0), &[&file[..at], &file[at..]]);
+            assert_eq!(rows, expected, "split at byte {}", at);
+            assert_eq!(ingest.failed, 0);
+        }
+        let bytes: Vec<&[u8]> = file.chunks(1).collect();
+        assert_eq!(ingest(options(None, 0), &bytes).1, expected);
+    }
+
+    #[test]
+    fn quoted_fields_keep_line_breaks_and_quotes() {
+        let file = b"k,v\na,\"line one\nline \"\"two\"\"\"\nb,\"\"\n\nc,plain";
+        let (_, rows) = ingest(options(None, 0), &[file]);
+        assert_eq!(
+            rows,
+            vec![
+                pair("a", "line one\nline \"two\""),
+                pair("b", ""),
+                pair("c", "plain")
+            ]
+        );
+    }
+
+    #[test]
+    fn spaces_belong_to_fields_but_not_to_header_names() {
+        let file = b"id , value\n a , 1 \n";
+        let (_, rows) = ingest(options(Some("id"), 0), &[file]);
+        assert_eq!(rows, vec![pair(" a ", " 1 ")]);
+    }
+
+    #[test]
+    fn skip_rows_drops_the_ticker_row() {
+        let file = b"Price,Close,Volume\nTicker,AAPL,AAPL\n2024-01-02,185.64,82488700\n";
+        let (ingest, rows) = ingest(options(Some("Price"), 1), &[file]);
+        assert_eq!(
+            rows,
+            vec![pair("2024-01-02", r#"{"Close":185.64,"Volume":82488700}"#)]
+        );
+        assert_eq!((ingest.rows, ingest.failed), (1, 0));
+    }
+
+    #[test]
+    fn rows_of_the_wrong_width_are_reported() {
+        let file = b"k,v\na,1\nb\nc,3,4\n,5\nd,4\n";
+        let (ingest, rows) = ingest(options(None, 0), &[file]);
+        assert_eq!(rows, vec![pair("a", "1"), pair("d", "4")]);
+        assert_eq!((ingest.rows, ingest.failed), (2, 3));
+        let errors: Vec<(u64, &str)> = ingest
+            .errors
+            .iter()
+            .map(|e| (e.row, e.error.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (3, "expected 2 fields, found 1"),
+                (4, "expected 2 fields, found 3"),
+                (5, "the key is empty"),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_numbers_that_read_back_the_same_become_numbers() {
+        let file = b"k,id,price,count,padded,exp\na,123456789012345678901234,185.64,-3, 1 ,1e3\n";
+        let (_, rows) = ingest(options(Some("k"), 0), &[file]);
+        assert_eq!(
+            rows,
+            vec![pair(
+                "a",
+                r#"{"count":-3,"exp":"1e3","id":"123456789012345678901234","padded":" 1 ","price":185.64}"#
+            )]
+        );
+        assert_eq!(field_value("18446744073709551615"), 18446744073709551615u64);
+        assert_eq!(field_value("18446744073709551616"), "18446744073709551616");
+    }
+
+    #[test]
+    fn missing_columns_fail_the_file() {
+        let mut ingest = CsvIngest::new(options(Some("Ticker"), 0));
+        assert!(ingest.feed(b"Price,Close\n").is_err());
+        assert!(CsvIngest::new(options(None, 0)).finish().is_err());
+    }
+}
@@ -1,5 +1,6 @@
 pub use super::*;
 pub mod chord;
 pub mod circula_buffer;
+pub mod ingest;
 
 const HTML_PATH: &str = "./src/chord_server/client/template.html";
@@ -0,0 +1,186 @@
+use super::*;
+use std::collections::BTreeMap;
+
+// The version of a value: the writes to its key it has seen, counted per node that made
+// them, including the writes of every version it replaced. A version descends another
+// when it has seen all of its writes; when neither descends the other they were written
+// concurrently (by nodes that didn't see each other's write, like both sides of a
+// partition) and are kept as siblings until a write that has seen both replaces them.
+//
+// A write made on top of an older read hasn't seen the writes of its node in between,
+// so its own write (the dot) is kept apart from the counts it has seen; counted in, it
+// would claim to have seen those writes too.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock {
+    #[serde(default)]
+    seen: BTreeMap<String, u64>,
+    #[serde(default)]
+    dot: Option<(String, u64)>,
+}
+
+impl VectorClock {
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty() && self.dot.is_none()
+    }
+
+    // The version of a write by `node` made on top of `context`, given the versions
+    // stored at the node. Its count is one past any write of the node seen so far.
+    pub fn write(context: &VectorClock, node: &str, stored: &[&VectorClock]) -> VectorClock {
+        let count = stored
+            .iter()
+            .chain(std::iter::once(&context))
+            .map(|clock| clock.latest(node))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut clock = VectorClock {
+            seen: context.flattened().seen,
+            dot: None,
+        };
+        if clock.latest(node) + 1 == count {
+            clock.seen.insert(node.to_string(), count);
+        } else {
+            clock.dot = Some((node.to_string(), count));
+        }
+        clock
+    }
+
+    // Every write seen by either clock, as plain counts. Used for the context a reader
+    // hands back with its next write, which then replaces everything it read.
+    pub fn merge(&mut self, other: &VectorClock) {
+        let mut merged = self.flattened();
+        for (node, count) in other.flattened().seen {
+            let entry = merged.seen.entry(node).or_default();
+            *entry = (*entry).max(count);
+        }
+        *self = merged;
+    }
+
+    // whether this clock has seen every write `other` has
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        other.seen.iter().all(|(node, &count)| {
+            let seen = self.seen.get(node).copied().unwrap_or(0);
+            seen >= count || (seen + 1 == count && self.has_dot(node, count))
+        }) && other
+            .dot
+            .as_ref()
+            .is_none_or(|(node, count)| self.has(node, *count))
+    }
+
+    // the latest write of `node` this clock has seen
+    fn latest(&self, node: &str) -> u64 {
+        let seen = self.seen.get(node).copied().unwrap_or(0);
+        match &self.dot {
+            Some((n, count)) if n == node => seen.max(*count),
+            _ => seen,
+        }
+    }
+
+    fn has(&self, node: &str, count: u64) -> bool {
+        self.seen.get(node).is_some_and(|&seen| seen >= count) || self.has_dot(node, count)
+    }
+
+    fn has_dot(&self, node: &str, count: u64) -> bool {
+        self.dot
+            .as_ref()
+            .is_some_and(|(n, c)| n == node && *c == count)
+    }
+
+    // the dot counted in with the rest
+    fn flattened(&self) -> VectorClock {
+        let mut seen = self.seen.clone();
+        if let Some((node, count)) = &self.dot {
+            let entry = seen.entry(node.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+        VectorClock { seen, dot: None }
+    }
+}
+
+// Stored as JSON text next to the value
+impl rusqlite::ToSql for VectorClock {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        Ok(rusqlite::types::ToSqlOutput::from(json))
+    }
+}
+
+impl rusqlite::types::FromSql for VectorClock {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?)
+            .map_err(|e| rusqlite::types::FromSqlError::Other(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(context: &VectorClock, node: &str, stored: &[&VectorClock]) -> VectorClock {
+        VectorClock::write(context, node, stored)
+    }
+
+    fn concurrent(a: &VectorClock, b: &VectorClock) -> bool {
+        !a.descends(b) && !b.descends(a)
+    }
+
+    #[test]
+    fn writes_in_order_descend_each_other() {
+        let empty = VectorClock::default();
+        let first = write(&empty, "a", &[]);
+        let second = write(&first, "a", &[&first]);
+        let third = write(&second, "b", &[&second]);
+        assert!(second.descends(&first) && !first.descends(&second));
+        assert!(third.descends(&first) && third.descends(&second));
+        assert!(third.descends(&third));
+        assert!(first.descends(&empty) && !empty.descends(&first));
+    }
+
+    #[test]
+    fn writes_on_different_nodes_are_concurrent() {
+        let empty = VectorClock::default();
+        let base = write(&empty, "a", &[]);
+        let left = write(&base, "a", &[&base]);
+        let right = write(&base, "b", &[&base]);
+        assert!(concurrent(&left, &right));
+    }
+
+    // a write made on top of an older read, after the same node wrote again, hasn't seen
+    // that write and doesn't claim to
+    #[test]
+    fn a_stale_context_stays_concurrent() {
+        let empty = VectorClock::default();
+        let read = write(&empty, "a", &[]);
+        let newer = write(&read, "a", &[&read]);
+        let stale = write(&read, "a", &[&newer]);
+        assert!(concurrent(&stale, &newer));
+        assert!(stale.descends(&read));
+    }
+
+    #[test]
+    fn a_merged_context_descends_what_it_merged() {
+        let empty = VectorClock::default();
+        let read = write(&empty, "a", &[]);
+        let newer = write(&read, "a", &[&read]);
+        let stale = write(&read, "a", &[&newer]);
+        let other = write(&empty, "b", &[]);
+
+        let mut context = VectorClock::default();
+        for clock in [&newer, &stale, &other] {
+            context.merge(clock);
+        }
+        assert!(context.descends(&newer) && context.descends(&stale) && context.descends(&other));
+
+        let resolved = write(&context, "b", &[&newer, &stale, &other]);
+        for clock in [&newer, &stale, &other] {
+            assert!(resolved.descends(clock) && !clock.descends(&resolved));
+        }
+
+        // merging is idempotent
+        let mut again = context.clone();
+        again.merge(&newer);
+        again.merge(&context);
+        assert_eq!(again, context);
+    }
+}
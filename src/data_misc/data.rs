@@ -1,9 +1,17 @@
 use super::*;
+use clock::VectorClock;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub(crate) struct Data {
     pub key: String,
     pub value: String,
+    // milliseconds since the epoch when the value was written, compared by KeepNewest
+    #[serde(default)]
+    pub timestamp: i64,
+    // the version the value belongs to, given by the node that stored it. In a write it
+    // is the version the writer read (its context), empty to replace every stored one
+    #[serde(default)]
+    pub clock: VectorClock,
 }
 
 pub trait NewData {
@@ -15,6 +23,206 @@ impl NewData for Data {
         Data {
             key: key.to_string(),
             value: value.to_string(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            clock: VectorClock::default(),
+        }
+    }
+}
+
+// How a write treats a key that already holds a value, picked per request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    // fail the whole batch, naming every key that already exists
+    InsertOnly,
+    // replace whatever the key holds
+    #[default]
+    Overwrite,
+    // replace it only with a value written later than the stored one
+    KeepNewest,
+    // keep the stored values and add the new one next to them
+    Append,
+}
+
+impl WriteMode {
+    // the name used in query strings and JSON bodies
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriteMode::InsertOnly => "insert_only",
+            WriteMode::Overwrite => "overwrite",
+            WriteMode::KeepNewest => "keep_newest",
+            WriteMode::Append => "append",
+        }
+    }
+}
+
+impl Data {
+    // A value holding a JSON object is a document, its fields can be queried
+    pub fn document(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        match serde_json::from_str(&self.value) {
+            Ok(serde_json::Value::Object(fields)) => Some(fields),
+            _ => None,
+        }
+    }
+
+    // How a lookup answers with it, documents as objects rather than escaped strings
+    pub fn to_json(&self) -> serde_json::Value {
+        let value = match self.document() {
+            Some(fields) => serde_json::Value::Object(fields),
+            None => serde_json::Value::from(self.value.clone()),
+        };
+        serde_json::json!({
+            "key": self.key,
+            "value": value,
+            "timestamp": self.timestamp,
+            "clock": self.clock
+        })
+    }
+
+    // The "value" of a PUT body: a string is stored as it is, an object as a document
+    pub fn value_from_json(value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::String(value) => Some(value.clone()),
+            serde_json::Value::Object(_) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    // A PUT body, {"value": ..} with an optional "mode" (a WriteMode, overwrite by default),
+    // "timestamp" (now by default) and "context", the clock of the versions it replaces
+    pub fn from_put(key: &str, body: &serde_json::Value) -> Result<(Data, WriteMode), String> {
+        let value = body
+            .get("value")
+            .and_then(Data::value_from_json)
+            .ok_or("Missing value in request, expected a string or a JSON object")?;
+        let mut data = Data::new(key, &value);
+        if let Some(timestamp) = body.get("timestamp").filter(|t| !t.is_null()) {
+            data.timestamp = match timestamp {
+                serde_json::Value::Number(ms) => ms.as_i64(),
+                serde_json::Value::String(text) => parse_timestamp(text),
+                _ => None,
+            }
+            .ok_or("Invalid timestamp, expected milliseconds, RFC 3339 or YYYY-MM-DD")?;
+        }
+        if let Some(context) = body.get("context").filter(|c| !c.is_null()) {
+            data.clock = serde_json::from_value(context.clone()).map_err(|_| {
+                "Invalid context, expected the one a lookup returned, like {\"seen\": {\"node:port\": 1}}"
+                    .to_string()
+            })?;
+        }
+        let mode = match body.get("mode").filter(|m| !m.is_null()) {
+            Some(mode) => serde_json::from_value(mode.clone()).map_err(|_| {
+                "Invalid mode, expected insert_only, overwrite, keep_newest or append".to_string()
+            })?,
+            None => WriteMode::default(),
+        };
+        Ok((data, mode))
+    }
+}
+
+// A write time given as milliseconds since the epoch, an RFC 3339 time or a YYYY-MM-DD
+// date (midnight UTC), in milliseconds since the epoch
+pub fn parse_timestamp(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Ok(ms) = text.parse::<i64>() {
+        return Some(ms);
+    }
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Some(time.timestamp_millis());
+    }
+    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc().timestamp_millis())
+}
+
+// What a bulk /insert answers with: how many rows each node stored, including the nodes
+// rows were forwarded to, and the rows that could not be stored
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InsertSummary {
+    pub acks: Vec<InsertAck>,
+    pub failed: Vec<InsertFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InsertAck {
+    pub node: String,
+    pub stored: usize,
+    // rows left out because the key kept its stored value (KeepNewest, Append)
+    #[serde(default)]
+    pub skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InsertFailure {
+    pub node: String,
+    pub rows: usize,
+    pub error: String,
+    // the keys that already existed, for InsertOnly
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+}
+
+impl InsertSummary {
+    pub fn stored(node: &str, stored: usize, skipped: usize) -> Self {
+        InsertSummary {
+            acks: vec![InsertAck {
+                node: node.to_string(),
+                stored,
+                skipped,
+            }],
+            failed: Vec::new(),
+        }
+    }
+
+    pub fn failure(node: &str, rows: usize, error: String) -> Self {
+        Self::conflict(node, rows, error, Vec::new())
+    }
+
+    pub fn conflict(node: &str, rows: usize, error: String, conflicts: Vec<String>) -> Self {
+        InsertSummary {
+            acks: Vec::new(),
+            failed: vec![InsertFailure {
+                node: node.to_string(),
+                rows,
+                error,
+                conflicts,
+            }],
+        }
+    }
+
+    pub fn stored_rows(&self) -> usize {
+        self.acks.iter().map(|ack| ack.stored).sum()
+    }
+
+    pub fn skipped_rows(&self) -> usize {
+        self.acks.iter().map(|ack| ack.skipped).sum()
+    }
+
+    pub fn failed_rows(&self) -> usize {
+        self.failed.iter().map(|failure| failure.rows).sum()
+    }
+
+    // Add up another summary, one entry per node
+    pub fn merge(&mut self, other: InsertSummary) {
+        for ack in other.acks {
+            match self.acks.iter_mut().find(|a| a.node == ack.node) {
+                Some(existing) => {
+                    existing.stored += ack.stored;
+                    existing.skipped += ack.skipped;
+                }
+                None => self.acks.push(ack),
+            }
+        }
+        for failure in other.failed {
+            match self.failed.iter_mut().find(|f| f.node == failure.node) {
+                Some(existing) => {
+                    existing.rows += failure.rows;
+                    existing.error = failure.error;
+                    existing.conflicts.extend(failure.conflicts);
+                }
+                None => self.failed.push(failure),
+            }
         }
     }
 }
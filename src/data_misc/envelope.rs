@@ -0,0 +1,239 @@
+use super::*;
+use msg::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// Version 1 was the bare Message without an envelope. Over HTTP messages are JSON, so
+// messages and fields may be added within a version as long as new fields are
+// #[serde(default)], older peers just ignore them. The bincode frames of the tcp transport
+// have no field names to skip unknown ones by: every change to a message or to what it
+// carries (Data, RpcRequest and the like) bumps PROTOCOL_VERSION, and tcp peers only take
+// frames of their own version. Anything older nodes can't work with over HTTP bumps it as
+// well. MIN_PROTOCOL_VERSION is the oldest version that can still share a ring with this
+// one: a node of version 4 stores values without their clock and one of version 3 ignores
+// write modes, so both would lose or overwrite versions behind the others' backs.
+//   2: the envelope
+//   3: lookups carry a query
+//   4: puts carry a write mode and their acks what became of the write
+//   5: values carry the vector clock of their version
+pub const PROTOCOL_VERSION: u32 = 5;
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+
+// the ip:port of this process, set once at startup
+static SENDER: OnceLock<String> = OnceLock::new();
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn set_sender(id: String) {
+    let _ = SENDER.set(id);
+}
+
+// What goes over the wire to /msg and /rpc (or in a tcp frame), the message along with
+// where it is from and which protocol version it speaks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<M = Message> {
+    pub version: u32,
+    #[serde(default)]
+    pub sender: String,
+    // unique per sender
+    #[serde(default)]
+    pub message_id: u64,
+    // milliseconds since the epoch, on the sender's clock
+    #[serde(default)]
+    pub timestamp: i64,
+    pub message: M,
+}
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    // a bare message, from a node older than the envelope
+    Unversioned,
+    Incompatible {
+        sender: String,
+        version: u32,
+    },
+    // a message this version doesn't know, most likely from a newer node
+    Unsupported {
+        sender: String,
+        version: u32,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvelopeError::Unversioned => write!(
+                f,
+                "message without a protocol version, the sender runs a release older than protocol version {}",
+                MIN_PROTOCOL_VERSION
+            ),
+            EnvelopeError::Incompatible { sender, version } => write!(
+                f,
+                "{} speaks protocol version {}, this node speaks version {} and needs at least {}",
+                sender, version, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+            ),
+            EnvelopeError::Unsupported {
+                sender,
+                version,
+                message,
+            } => write!(
+                f,
+                "{} (protocol version {}) sent a {} message, which this node does not know",
+                sender, version, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+impl EnvelopeError {
+    // what /msg answers with, 426 for a peer that has to be upgraded before it can take part
+    pub fn status(&self) -> u16 {
+        match self {
+            EnvelopeError::Unversioned | EnvelopeError::Incompatible { .. } => 426,
+            EnvelopeError::Unsupported { .. } => 422,
+        }
+    }
+}
+
+impl<M> Envelope<M> {
+    pub fn new(message: M) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            sender: SENDER.get().cloned().unwrap_or_default(),
+            message_id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            message,
+        }
+    }
+
+    // Newer peers are fine, they only add what we ignore or turn down one message at a time
+    pub fn check_version(&self) -> Result<(), EnvelopeError> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(EnvelopeError::Incompatible {
+                sender: self.sender.clone(),
+                version: self.version,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<M: serde::de::DeserializeOwned> Envelope<M> {
+    // Open a JSON body posted to /msg (or an RpcRequest posted to /rpc). Unknown fields are
+    // skipped, an unknown message is reported by name rather than as a parse error.
+    pub fn from_json(body: serde_json::Value) -> Result<Envelope<M>, EnvelopeError> {
+        let envelope: Envelope<serde_json::Value> =
+            serde_json::from_value(body).map_err(|_| EnvelopeError::Unversioned)?;
+        envelope.check_version()?;
+
+        let message = serde_json::from_value(envelope.message.clone()).map_err(|_| {
+            EnvelopeError::Unsupported {
+                sender: envelope.sender.clone(),
+                version: envelope.version,
+                message: message_name(&envelope.message),
+            }
+        })?;
+        Ok(Envelope {
+            version: envelope.version,
+            sender: envelope.sender,
+            message_id: envelope.message_id,
+            timestamp: envelope.timestamp,
+            message,
+        })
+    }
+}
+
+// The variant name of a serialized Message, "Kys" or the key of {"Ping": {...}}
+fn message_name(message: &serde_json::Value) -> String {
+    match message {
+        serde_json::Value::String(name) => name.clone(),
+        serde_json::Value::Object(fields) => fields.keys().next().cloned().unwrap_or_default(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn opened(body: serde_json::Value) -> Result<Envelope, EnvelopeError> {
+        Envelope::from_json(body)
+    }
+
+    #[test]
+    fn current_and_newer_versions_are_taken() {
+        let envelope = Envelope::new(Message::Joined {
+            node_id: "10.0.0.1:3001".to_string(),
+        });
+        let received = opened(json!(envelope)).unwrap();
+        assert_eq!(received.version, PROTOCOL_VERSION);
+        assert_eq!(received.message_id, envelope.message_id);
+        assert!(
+            matches!(received.message, Message::Joined { node_id } if node_id == "10.0.0.1:3001")
+        );
+
+        let mut newer = json!(Envelope::new(Message::Kys));
+        newer["version"] = json!(PROTOCOL_VERSION + 1);
+        // fields a newer node added are skipped
+        newer["priority"] = json!(1);
+        assert!(opened(newer).is_ok());
+    }
+
+    #[test]
+    fn versions_below_the_minimum_are_incompatible() {
+        let mut envelope = Envelope::new(Message::Kys);
+        envelope.version = MIN_PROTOCOL_VERSION;
+        assert!(envelope.check_version().is_ok());
+
+        // write modes and value clocks came in 4 and 5, older nodes can't share the ring
+        const { assert!(MIN_PROTOCOL_VERSION >= 5) };
+        for version in 1..MIN_PROTOCOL_VERSION {
+            envelope.version = version;
+            assert!(envelope.check_version().is_err());
+            match opened(json!(envelope)) {
+                Err(e @ EnvelopeError::Incompatible { .. }) => {
+                    assert_eq!(e.status(), 426);
+                    assert!(e
+                        .to_string()
+                        .contains(&format!("protocol version {}", version)));
+                }
+                other => panic!("version {} was {:?}", version, other),
+            }
+        }
+    }
+
+    #[test]
+    fn bare_messages_are_unversioned() {
+        for body in [
+            json!(Message::Kys),
+            json!("Kys"),
+            json!({ "message": "Kys" }),
+        ] {
+            match opened(body) {
+                Err(e @ EnvelopeError::Unversioned) => assert_eq!(e.status(), 426),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_messages_are_named() {
+        let mut body = json!(Envelope::new(Message::Kys));
+        for (message, name) in [
+            (json!({ "Teleport": { "to": "10.0.0.2:3001" } }), "Teleport"),
+            (json!("SelfDestruct"), "SelfDestruct"),
+        ] {
+            body["message"] = message;
+            match opened(body.clone()) {
+                Err(e @ EnvelopeError::Unsupported { .. }) => {
+                    assert_eq!(e.status(), 422);
+                    assert!(e.to_string().contains(name));
+                }
+                other => panic!("{} was {:?}", name, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use super::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters of the message dispatcher, served on GET /metrics
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // messages taken off the channel
+    received: AtomicU64,
+    // of those, the read-only ones handled in a task of their own
+    concurrent: AtomicU64,
+    // messages turned away with a 503 because the channel was full
+    rejected: AtomicU64,
+    // handlers that returned an error
+    failed: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsReport {
+    pub received: u64,
+    pub concurrent: u64,
+    pub rejected: u64,
+    pub failed: u64,
+    // messages waiting in the channel, out of queue_capacity
+    pub queued: usize,
+    pub queue_capacity: usize,
+    // read-only handlers running right now, out of max_concurrency
+    pub in_flight: usize,
+    pub max_concurrency: usize,
+}
+
+impl Metrics {
+    pub fn received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn concurrent(&self) {
+        self.concurrent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(
+        &self,
+        queued: usize,
+        queue_capacity: usize,
+        in_flight: usize,
+        max_concurrency: usize,
+    ) -> MetricsReport {
+        MetricsReport {
+            received: self.received.load(Ordering::Relaxed),
+            concurrent: self.concurrent.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            queued,
+            queue_capacity,
+            in_flight,
+            max_concurrency,
+        }
+    }
+}
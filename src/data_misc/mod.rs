@@ -1,4 +1,10 @@
 use super::*;
+pub mod clock;
 pub mod data;
+pub mod envelope;
+pub mod metrics;
 pub mod msg;
 pub mod node_state;
+pub mod pending;
+pub mod query;
+pub mod replies;
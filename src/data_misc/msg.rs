@@ -1,6 +1,9 @@
 use super::*;
+use crate::gossip::gossip::Member;
 use data::*;
+use query::Query;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Message {
     IAmYourSuccessor {
@@ -15,10 +18,6 @@ pub enum Message {
     ResKnownNode {
         node_id: String,
     },
-    Data {
-        from: String,
-        data: Vec<Data>,
-    },
     ReqJoin {
         node_id: String,
     },
@@ -34,31 +33,76 @@ pub enum Message {
         error: String,
     },
     LookupReq {
+        id: u64,
         key: String,
         hops: usize,
+        reply_to: String,
+        // nodes the request has visited so far
+        path: Vec<String>,
+        #[serde(default)]
+        query: Query,
     },
     LookupRes {
+        id: u64,
         key: String,
         hops: usize,
+        node: Option<String>,
         data: Option<Vec<Data>>,
+        path: Vec<String>,
     },
-    NodeExists,
-    Notify {
-        node_id: String,
+    Put {
+        id: u64,
+        data: Data,
+        #[serde(default)]
+        mode: WriteMode,
+        hops: usize,
+        reply_to: String,
     },
-    Leave {
-        node_id: String,
+    PutAck {
+        id: u64,
+        key: String,
+        hops: usize,
+        node: Option<String>,
+        // the key kept its stored value, a newer one or the same one appended before
+        #[serde(default)]
+        skipped: bool,
+        // why the node holding the key turned the write down, like an insert_only conflict
+        #[serde(default)]
+        error: Option<String>,
+    },
+    Delete {
+        id: u64,
+        key: String,
+        hops: usize,
+        reply_to: String,
     },
-    Joined {
-        node_id: String,
+    DeleteAck {
+        id: u64,
+        key: String,
+        hops: usize,
+        node: Option<String>,
+        removed: usize,
     },
-    ReqFinger {
+    Replicate {
         from: String,
-        index: usize,
+        data: Vec<Data>,
+        removed: Vec<String>,
+    },
+    ReplicaLookupReq {
+        id: u64,
+        key: String,
+        hops: usize,
+        reply_to: String,
+        path: Vec<String>,
+        #[serde(default)]
+        query: Query,
+    },
+    NodeExists,
+    Leave {
+        node_id: String,
     },
-    ResFinger {
+    Joined {
         node_id: String,
-        index: usize,
     },
     CheckNode {
         node_id: String,
@@ -70,7 +114,20 @@ pub enum Message {
         from: String,
         successor: String,
     },
-    Ping,
-    Pong,
+    // liveness checks double as gossip, carrying membership updates both ways
+    Ping {
+        #[serde(default)]
+        gossip: Vec<Member>,
+    },
+    Pong {
+        #[serde(default)]
+        gossip: Vec<Member>,
+    },
+    // ask a member to ping `target` on our behalf, answered with a Pong if it responded
+    PingReq {
+        target: String,
+        #[serde(default)]
+        gossip: Vec<Member>,
+    },
     Kys,
 }
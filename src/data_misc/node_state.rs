@@ -25,3 +25,25 @@ impl NodeState {
         }
     }
 }
+
+// Virtual node 0 keeps the plain ip:port id, the others are served under /v/<n> on it
+pub fn vnode_id(host: &str, vnode: usize) -> String {
+    if vnode == 0 {
+        host.to_string()
+    } else {
+        format!("{}/v/{}", host, vnode)
+    }
+}
+
+// The ip:port of the process hosting a (possibly virtual) node id
+pub fn host_of(id: &str) -> &str {
+    id.split("/v/").next().unwrap_or(id)
+}
+
+// Which of its host's virtual nodes an id is, 0 for the plain ip:port
+pub fn vnode_of(id: &str) -> usize {
+    id.split("/v/")
+        .nth(1)
+        .and_then(|vnode| vnode.parse().ok())
+        .unwrap_or(0)
+}
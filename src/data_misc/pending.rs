@@ -0,0 +1,58 @@
+use super::*;
+use msg::Message;
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+
+// Table of requests waiting for a response message, keyed by correlation id
+#[derive(Debug, Clone, Default)]
+pub struct PendingRequests {
+    requests: Arc<Mutex<HashMap<u64, oneshot::Sender<Message>>>>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        PendingRequests {
+            requests: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Reserve a fresh correlation id and return the receiver its response will arrive on
+    pub async fn register(&self) -> (u64, oneshot::Receiver<Message>) {
+        let (tx, rx) = oneshot::channel();
+        let mut requests = self.requests.lock().await;
+        let mut id = rand::random::<u64>();
+        while requests.contains_key(&id) {
+            id = rand::random::<u64>();
+        }
+        requests.insert(id, tx);
+        (id, rx)
+    }
+
+    // Hand a response to whoever is waiting on `id`, returns false if nobody is
+    pub async fn resolve(&self, id: u64, message: Message) -> bool {
+        match self.requests.lock().await.remove(&id) {
+            Some(tx) => tx.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    pub async fn cancel(&self, id: u64) {
+        self.requests.lock().await.remove(&id);
+    }
+
+    // Wait for the response to `id`, dropping the entry if it does not arrive in time
+    pub async fn wait(
+        &self,
+        id: u64,
+        rx: oneshot::Receiver<Message>,
+        timeout: std::time::Duration,
+    ) -> Option<Message> {
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(message)) => Some(message),
+            _ => {
+                self.cancel(id).await;
+                None
+            }
+        }
+    }
+}
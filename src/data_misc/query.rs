@@ -0,0 +1,291 @@
+use super::*;
+use data::Data;
+use serde_json::{Map, Value};
+
+// What a lookup wants from the values it finds: only documents matching every filter,
+// cut down to `fields` (all of them when empty). Applied by the node holding the key,
+// so only the requested fields travel back.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    #[serde(default)]
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub filter: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    pub field: String,
+    pub op: FilterOp,
+    // compared as a number when both sides are one, as text otherwise
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+// `?fields=` and `?filter=` of GET /kv/{key}, both comma separated
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryParams {
+    pub fields: Option<String>,
+    pub filter: Option<String>,
+}
+
+impl QueryParams {
+    pub fn parse(&self) -> Result<Query, String> {
+        let split = |list: &Option<String>| -> Vec<String> {
+            list.iter()
+                .flat_map(|l| l.split(','))
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        Ok(Query {
+            fields: split(&self.fields),
+            filter: split(&self.filter)
+                .iter()
+                .map(|f| f.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    // field<op>value, with op one of = != > >= < <=
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        const OPS: [(&str, FilterOp); 6] = [
+            ("!=", FilterOp::Ne),
+            (">=", FilterOp::Ge),
+            ("<=", FilterOp::Le),
+            ("=", FilterOp::Eq),
+            (">", FilterOp::Gt),
+            ("<", FilterOp::Lt),
+        ];
+        // the leftmost operator splits, two-character ones win over their prefix
+        let (at, symbol, op) = OPS
+            .iter()
+            .filter_map(|(symbol, op)| expression.find(symbol).map(|at| (at, *symbol, *op)))
+            .min_by_key(|(at, symbol, _)| (*at, std::cmp::Reverse(symbol.len())))
+            .ok_or_else(|| {
+                format!(
+                    "Filter '{}' has no operator, expected one of = != > >= < <=",
+                    expression
+                )
+            })?;
+        let field = expression[..at].trim();
+        if field.is_empty() {
+            return Err(format!("Filter '{}' has no field", expression));
+        }
+        Ok(Filter {
+            field: field.to_string(),
+            op,
+            value: expression[at + symbol.len()..].trim().to_string(),
+        })
+    }
+}
+
+impl Query {
+    // The fields and filters of a POST /lookup body, given as lists or comma separated
+    pub fn from_body(body: &Value) -> Result<Query, String> {
+        let list = |name: &str| -> Result<Option<String>, String> {
+            match body.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(list)) => Ok(Some(list.clone())),
+                Some(Value::Array(items)) => items
+                    .iter()
+                    .map(|item| item.as_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| Some(items.join(",")))
+                    .ok_or_else(|| format!("'{}' must be a list of strings", name)),
+                Some(_) => Err(format!("'{}' must be a list of strings", name)),
+            }
+        };
+        QueryParams {
+            fields: list("fields")?,
+            filter: list("filter")?,
+        }
+        .parse()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.filter.is_empty()
+    }
+
+    // Drop the values that don't match and project the rest. Plain (non-document) values
+    // have no fields, they pass only when there is no filter and are never projected.
+    pub fn apply(&self, data: Vec<Data>) -> Vec<Data> {
+        if self.is_empty() {
+            return data;
+        }
+        data.into_iter()
+            .filter_map(|item| match item.document() {
+                Some(document) => {
+                    if !self.filter.iter().all(|f| f.matches(&document)) {
+                        return None;
+                    }
+                    if self.fields.is_empty() {
+                        return Some(item);
+                    }
+                    let projected: Map<String, Value> = self
+                        .fields
+                        .iter()
+                        .filter_map(|field| document.get(field).map(|v| (field.clone(), v.clone())))
+                        .collect();
+                    Some(Data {
+                        key: item.key,
+                        value: Value::Object(projected).to_string(),
+                        timestamp: item.timestamp,
+                        clock: item.clock,
+                    })
+                }
+                None if self.filter.is_empty() => Some(item),
+                None => None,
+            })
+            .collect()
+    }
+}
+
+impl Filter {
+    fn matches(&self, document: &Map<String, Value>) -> bool {
+        let Some(field) = document.get(&self.field) else {
+            return false;
+        };
+        let ordering = match (field, self.value.parse::<f64>()) {
+            (Value::Number(n), Ok(value)) => n.as_f64().and_then(|n| n.partial_cmp(&value)),
+            (Value::String(s), _) => Some(s.as_str().cmp(self.value.as_str())),
+            (other, _) => Some(other.to_string().as_str().cmp(self.value.as_str())),
+        };
+        let Some(ordering) = ordering else {
+            return false;
+        };
+        match self.op {
+            FilterOp::Eq => ordering.is_eq(),
+            FilterOp::Ne => ordering.is_ne(),
+            FilterOp::Gt => ordering.is_gt(),
+            FilterOp::Ge => ordering.is_ge(),
+            FilterOp::Lt => ordering.is_lt(),
+            FilterOp::Le => ordering.is_le(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::NewData;
+    use serde_json::json;
+
+    fn filter(expression: &str) -> (String, FilterOp, String) {
+        let f: Filter = expression.parse().unwrap();
+        (f.field, f.op, f.value)
+    }
+
+    fn query(fields: &str, filter: &str) -> Query {
+        Query::from_body(&json!({ "fields": fields, "filter": filter })).unwrap()
+    }
+
+    fn values(query: &Query, values: &[&str]) -> Vec<String> {
+        let data = values.iter().map(|v| Data::new("k", v)).collect();
+        query.apply(data).into_iter().map(|d| d.value).collect()
+    }
+
+    #[test]
+    fn filters_split_on_their_leftmost_operator() {
+        let cases = [
+            ("price=10", ("price", FilterOp::Eq, "10")),
+            ("price!=10", ("price", FilterOp::Ne, "10")),
+            ("price>10", ("price", FilterOp::Gt, "10")),
+            ("price>=10", ("price", FilterOp::Ge, "10")),
+            ("price<10", ("price", FilterOp::Lt, "10")),
+            ("price<=10", ("price", FilterOp::Le, "10")),
+            (" price >= 10 ", ("price", FilterOp::Ge, "10")),
+            // what follows the operator is all value
+            ("note=a>=b", ("note", FilterOp::Eq, "a>=b")),
+            ("note!==", ("note", FilterOp::Ne, "=")),
+            ("note=", ("note", FilterOp::Eq, "")),
+        ];
+        for (expression, (field, op, value)) in cases {
+            assert_eq!(
+                filter(expression),
+                (field.to_string(), op, value.to_string()),
+                "{}",
+                expression
+            );
+        }
+        assert!("price"
+            .parse::<Filter>()
+            .unwrap_err()
+            .contains("no operator"));
+        assert!(" >=10".parse::<Filter>().unwrap_err().contains("no field"));
+    }
+
+    #[test]
+    fn lists_come_as_strings_or_arrays() {
+        let expected = Query {
+            fields: vec!["Close".to_string(), "Volume".to_string()],
+            filter: vec!["Close>100".parse().unwrap()],
+        };
+        assert_eq!(query(" Close, ,Volume,", "Close>100"), expected);
+        let body = json!({ "key": "k", "fields": ["Close", "Volume"], "filter": ["Close>100"] });
+        assert_eq!(Query::from_body(&body).unwrap(), expected);
+        assert_eq!(
+            Query::from_body(&json!({ "key": "k", "fields": null })).unwrap(),
+            Query::default()
+        );
+
+        assert!(Query::from_body(&json!({ "fields": 3 })).is_err());
+        assert!(Query::from_body(&json!({ "filter": ["a=1", 2] })).is_err());
+        assert!(Query::from_body(&json!({ "filter": "a=1,b" })).is_err());
+    }
+
+    #[test]
+    fn numbers_compare_as_numbers_and_the_rest_as_text() {
+        let data = [
+            r#"{"n":10}"#,
+            r#"{"n":9.5}"#,
+            r#"{"n":"10"}"#,
+            r#"{"n":true}"#,
+        ];
+        // 10 > 9.6 as numbers, but "10" < "9.6" as text
+        assert_eq!(values(&query("", "n>9.6"), &data), vec![data[0], data[3]]);
+        assert_eq!(values(&query("", "n<=9.5"), &data), vec![data[1], data[2]]);
+        assert_eq!(values(&query("", "n=10"), &data), vec![data[0], data[2]]);
+        assert_eq!(values(&query("", "n=true"), &data), vec![data[3]]);
+        // a number never equals text
+        assert_eq!(values(&query("", "n=ten"), &data), Vec::<String>::new());
+        assert_eq!(
+            values(&query("", "n>9,n<11"), &data),
+            vec![data[0], data[1]]
+        );
+    }
+
+    #[test]
+    fn documents_missing_the_field_never_match() {
+        let data = [r#"{"a":1}"#, r#"{"b":1}"#];
+        assert_eq!(values(&query("", "a=1"), &data), vec![data[0]]);
+        assert_eq!(values(&query("", "a!=2"), &data), vec![data[0]]);
+    }
+
+    #[test]
+    fn plain_values_pass_only_without_a_filter() {
+        let data = ["plain", "[1,2]", r#"{"a":1,"b":2}"#];
+        assert_eq!(values(&Query::default(), &data), data);
+        // fields leave values that aren't documents as they are, and skip missing ones
+        assert_eq!(
+            values(&query("a,c", ""), &data),
+            vec!["plain", "[1,2]", r#"{"a":1}"#]
+        );
+        assert_eq!(values(&query("", "a=1"), &data), vec![data[2]]);
+        assert_eq!(values(&query("b", "a=1"), &data), vec![r#"{"b":2}"#]);
+    }
+}
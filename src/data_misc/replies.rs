@@ -0,0 +1,164 @@
+use super::*;
+use clock::VectorClock;
+use data::Data;
+use msg::Message;
+use query::Query;
+
+// Turn the LookupRes a lookup waited for (or None on timeout) into the HTTP answer
+pub fn lookup_reply(key: &str, query: &Query, res: Option<Message>) -> HttpResponse {
+    match res {
+        Some(Message::LookupRes {
+            key,
+            hops,
+            node,
+            data: Some(data),
+            path,
+            ..
+        }) => {
+            let message = if data.is_empty() && !query.filter.is_empty() {
+                format!(
+                    "Key '{}' has no value matching the filter ({} hops)",
+                    key, hops
+                )
+            } else if data.is_empty() {
+                format!("Key '{}' not found ({} hops)", key, hops)
+            } else {
+                format!(
+                    "Key '{}' found on node {} ({} hops)",
+                    key,
+                    node.as_deref().unwrap_or("?"),
+                    hops
+                )
+            };
+            // Values grouped by version, more than one when the key was written concurrently.
+            // A put with the merged clock as its "context" replaces all of them.
+            let mut versions: Vec<(&VectorClock, Vec<serde_json::Value>)> = Vec::new();
+            let mut context = VectorClock::default();
+            for item in &data {
+                let value = item.to_json()["value"].take();
+                match versions.iter_mut().find(|(clock, _)| **clock == item.clock) {
+                    Some((_, values)) => values.push(value),
+                    None => versions.push((&item.clock, vec![value])),
+                }
+                context.merge(&item.clock);
+            }
+            let body = serde_json::json!({
+                "status": if data.is_empty() { "error" } else { "success" },
+                "message": message,
+                "key": key,
+                "data": data.iter().map(Data::to_json).collect::<Vec<_>>(),
+                "versions": versions
+                    .iter()
+                    .map(|(clock, values)| serde_json::json!({ "clock": clock, "values": values }))
+                    .collect::<Vec<_>>(),
+                "siblings": versions.len() > 1,
+                "context": context,
+                "hops": hops,
+                "node": node,
+                "path": path
+            });
+            if data.is_empty() {
+                HttpResponse::NotFound().json(body)
+            } else {
+                HttpResponse::Ok().json(body)
+            }
+        }
+        Some(Message::LookupRes {
+            key, hops, path, ..
+        }) => HttpResponse::BadGateway().json(serde_json::json!({
+            "status": "error",
+            "message": if hops + 1 >= rpc::MAX_HOPS {
+                format!("Lookup for key '{}' not delivered after {} hops", key, hops + 1)
+            } else {
+                format!("Lookup for key '{}' could not be routed", key)
+            },
+            "key": key,
+            "hops": hops,
+            "path": path
+        })),
+        _ => HttpResponse::GatewayTimeout().json(serde_json::json!({
+            "status": "error",
+            "message": format!("Lookup for key '{}' timed out", key),
+            "key": key
+        })),
+    }
+}
+
+// Turn the PutAck/DeleteAck a write waited for (or None on timeout) into the HTTP answer
+pub fn write_reply(key: &str, res: Option<Message>) -> HttpResponse {
+    match res {
+        Some(Message::PutAck {
+            key,
+            hops,
+            node: Some(node),
+            error: Some(error),
+            ..
+        }) => HttpResponse::Conflict().json(serde_json::json!({
+            "status": "error",
+            "message": format!("Key '{}' not stored on node {}: {}", key, node, error),
+            "key": key,
+            "hops": hops,
+            "node": node
+        })),
+        Some(Message::PutAck {
+            key,
+            hops,
+            node: Some(node),
+            skipped,
+            ..
+        }) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "success",
+            "message": if skipped {
+                format!("Key '{}' kept its stored value on node {} ({} hops)", key, node, hops)
+            } else {
+                format!("Key '{}' stored on node {} ({} hops)", key, node, hops)
+            },
+            "key": key,
+            "hops": hops,
+            "node": node,
+            "stored": !skipped
+        })),
+        Some(Message::DeleteAck {
+            key,
+            hops,
+            node: Some(node),
+            removed,
+            ..
+        }) => {
+            let body = serde_json::json!({
+                "status": if removed > 0 { "success" } else { "error" },
+                "message": if removed > 0 {
+                    format!("Key '{}' deleted from node {} ({} hops)", key, node, hops)
+                } else {
+                    format!("Key '{}' not found ({} hops)", key, hops)
+                },
+                "key": key,
+                "hops": hops,
+                "node": node,
+                "removed": removed
+            });
+            if removed > 0 {
+                HttpResponse::Ok().json(body)
+            } else {
+                HttpResponse::NotFound().json(body)
+            }
+        }
+        Some(Message::PutAck { key, hops, .. }) | Some(Message::DeleteAck { key, hops, .. }) => {
+            HttpResponse::BadGateway().json(serde_json::json!({
+                "status": "error",
+                "message": if hops + 1 >= rpc::MAX_HOPS {
+                    format!("Write for key '{}' not delivered after {} hops", key, hops + 1)
+                } else {
+                    format!("Write for key '{}' could not be routed", key)
+                },
+                "key": key,
+                "hops": hops
+            }))
+        }
+        _ => HttpResponse::GatewayTimeout().json(serde_json::json!({
+            "status": "error",
+            "message": format!("Write for key '{}' timed out", key),
+            "key": key
+        })),
+    }
+}
@@ -0,0 +1,341 @@
+use super::*;
+use msg::Message;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// What one process believes about a member, the unit that gets gossiped
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Member {
+    pub id: String,
+    // bumped only by the member itself, to refute suspicion about it
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+#[derive(Debug)]
+struct Entry {
+    member: Member,
+    changed: Instant,
+    // how many more messages this update is piggybacked on
+    retransmits: usize,
+}
+
+#[derive(Debug, Default)]
+struct View {
+    // ids hosted by this process, suspicion about them gets refuted
+    local: Vec<String>,
+    members: HashMap<String, Entry>,
+}
+
+// SWIM-style membership view: members are probed one per interval, with indirect probes
+// through other members before they are suspected, and every change rides along on the
+// Ping/Pong messages already flowing between nodes
+#[derive(Debug, Clone)]
+pub struct Membership {
+    view: Arc<Mutex<View>>,
+    pub logs: Arc<Mutex<Vec<String>>>,
+}
+
+impl Membership {
+    pub fn new(logs: Arc<Mutex<Vec<String>>>) -> Self {
+        Membership {
+            view: Arc::new(Mutex::new(View::default())),
+            logs,
+        }
+    }
+
+    // Start speaking for `id`. The incarnation is the start time, so a restarted node
+    // overrides whatever the others remember about its previous run.
+    pub async fn add_local(&self, id: &str) {
+        let incarnation = chrono::Utc::now().timestamp() as u64;
+        let mut view = self.view.lock().await;
+        if !view.local.iter().any(|l| l == id) {
+            view.local.push(id.to_string());
+        }
+        let incarnation = match view.members.get(id) {
+            Some(entry) => incarnation.max(entry.member.incarnation + 1),
+            None => incarnation,
+        };
+        view.set(id, incarnation, MemberState::Alive);
+    }
+
+    // Announce that `id` left on purpose and stop refuting it
+    pub async fn leave(&self, id: &str) {
+        let mut view = self.view.lock().await;
+        view.local.retain(|l| l != id);
+        let incarnation = view.incarnation(id);
+        view.set(id, incarnation, MemberState::Dead);
+    }
+
+    // A member we heard of outside gossip (joins, successor lists), added only if unknown
+    pub async fn discover(&self, id: &str) {
+        let mut view = self.view.lock().await;
+        if !view.members.contains_key(id) {
+            view.set(id, 0, MemberState::Alive);
+        }
+    }
+
+    pub async fn suspect(&self, id: &str) {
+        let mut view = self.view.lock().await;
+        if view.local.iter().any(|l| l == id) {
+            return;
+        }
+        if let Some(entry) = view.members.get(id) {
+            if entry.member.state == MemberState::Alive {
+                let incarnation = entry.member.incarnation;
+                view.set(id, incarnation, MemberState::Suspect);
+                drop(view);
+                log_message!(self, "Suspecting member {}", id);
+            }
+        }
+    }
+
+    pub async fn dead(&self, id: &str) {
+        let mut view = self.view.lock().await;
+        if view.local.iter().any(|l| l == id) {
+            return;
+        }
+        let incarnation = view.incarnation(id);
+        if view.state(id) != Some(MemberState::Dead) {
+            view.set(id, incarnation, MemberState::Dead);
+        }
+    }
+
+    // Apply gossip from another process: a higher incarnation wins, and at the same
+    // incarnation Dead beats Suspect beats Alive
+    pub async fn merge(&self, updates: Vec<Member>) {
+        let mut refuted = Vec::new();
+        let mut changed = Vec::new();
+        {
+            let mut view = self.view.lock().await;
+            for update in updates {
+                let current = view.members.get(&update.id).map(|e| e.member.clone());
+
+                if view.local.contains(&update.id) {
+                    // someone thinks we are down, outrank that with a fresh incarnation
+                    if let Some(current) = current {
+                        if update.state != MemberState::Alive
+                            && update.incarnation >= current.incarnation
+                        {
+                            view.set(&update.id, update.incarnation + 1, MemberState::Alive);
+                            refuted.push(update.id);
+                        }
+                    }
+                    continue;
+                }
+
+                let newer = match &current {
+                    None => true,
+                    Some(current) => {
+                        update.incarnation > current.incarnation
+                            || (update.incarnation == current.incarnation
+                                && update.state > current.state)
+                    }
+                };
+                if newer {
+                    if current.map(|c| c.state) != Some(update.state) {
+                        changed.push((update.id.clone(), update.state));
+                    }
+                    view.set(&update.id, update.incarnation, update.state);
+                }
+            }
+        }
+
+        for id in refuted {
+            log_message!(self, "Refuted suspicion about {}", id);
+        }
+        for (id, state) in changed {
+            log_message!(self, "Member {} is now {:?}", id, state);
+        }
+    }
+
+    // Updates to piggyback on the next message, the least disseminated first
+    pub async fn piggyback(&self) -> Vec<Member> {
+        let mut view = self.view.lock().await;
+        let mut pending: Vec<&mut Entry> = view
+            .members
+            .values_mut()
+            .filter(|e| e.retransmits > 0)
+            .collect();
+        pending.sort_by_key(|e| std::cmp::Reverse(e.retransmits));
+        pending
+            .into_iter()
+            .take(MAX_PIGGYBACK)
+            .map(|e| {
+                e.retransmits -= 1;
+                e.member.clone()
+            })
+            .collect()
+    }
+
+    // The whole view, ordered by id
+    pub async fn members(&self) -> Vec<Member> {
+        let view = self.view.lock().await;
+        let mut members: Vec<Member> = view.members.values().map(|e| e.member.clone()).collect();
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+
+    // Members that are not known to be dead
+    pub async fn live_members(&self) -> Vec<String> {
+        self.members()
+            .await
+            .into_iter()
+            .filter(|m| m.state != MemberState::Dead)
+            .map(|m| m.id)
+            .collect()
+    }
+
+    // Ping `target` with our pending updates and merge the ones that come back
+    pub async fn ping(&self, target: &str) -> bool {
+        let gossip = self.piggyback().await;
+        self.exchange(target, Message::Ping { gossip }).await
+    }
+
+    // Ask `helper` to ping `target` for us
+    pub async fn ping_req(&self, helper: &str, target: &str) -> bool {
+        let gossip = self.piggyback().await;
+        self.exchange(
+            helper,
+            Message::PingReq {
+                target: target.to_string(),
+                gossip,
+            },
+        )
+        .await
+    }
+
+    async fn exchange(&self, node: &str, message: Message) -> bool {
+        let timeout = Duration::from_secs(*REQUEST_TIMEOUT);
+        let request = async { send_message!(node, message, 1).ok() };
+        match tokio::time::timeout(timeout, request).await {
+            Ok(Some(Message::Pong { gossip })) => {
+                self.merge(gossip).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Pull a peer's whole view, used when joining and by observers like the ChordRing
+    pub async fn sync_from(&self, node: &str) -> bool {
+        let timeout = Duration::from_secs(*REQUEST_TIMEOUT);
+        let request = async {
+            let response = send_get_request!(&format!("http://{}/members", node), 1).ok()?;
+            response.json::<Vec<Member>>().await.ok()
+        };
+        match tokio::time::timeout(timeout, request).await {
+            Ok(Some(members)) => {
+                self.merge(members).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Probe one random member per interval, in the background
+    pub fn start(&self) {
+        let membership = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(*GOSSIP_INTERVAL));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                membership.probe_round().await;
+            }
+        });
+    }
+
+    async fn probe_round(&self) {
+        self.expire_suspects().await;
+
+        let (target, helpers) = {
+            let view = self.view.lock().await;
+            let mut candidates: Vec<&String> = view
+                .members
+                .values()
+                .filter(|e| e.member.state != MemberState::Dead)
+                .map(|e| &e.member.id)
+                .filter(|id| !view.local.contains(id))
+                .collect();
+            candidates.shuffle(&mut rand::thread_rng());
+            match candidates.split_first() {
+                Some((target, rest)) => (
+                    (*target).clone(),
+                    rest.iter()
+                        .take(INDIRECT_PROBES)
+                        .map(|id| (*id).clone())
+                        .collect::<Vec<_>>(),
+                ),
+                None => return,
+            }
+        };
+
+        if self.ping(&target).await {
+            return;
+        }
+        for helper in helpers {
+            if self.ping_req(&helper, &target).await {
+                return;
+            }
+        }
+        self.suspect(&target).await;
+    }
+
+    // Declare members dead once they have been suspect for SUSPECT_ROUNDS intervals
+    async fn expire_suspects(&self) {
+        let timeout = Duration::from_millis(*GOSSIP_INTERVAL) * SUSPECT_ROUNDS;
+        let expired: Vec<String> = {
+            let mut view = self.view.lock().await;
+            let expired: Vec<(String, u64)> = view
+                .members
+                .values()
+                .filter(|e| e.member.state == MemberState::Suspect && e.changed.elapsed() > timeout)
+                .map(|e| (e.member.id.clone(), e.member.incarnation))
+                .collect();
+            for (id, incarnation) in expired.iter() {
+                view.set(id, *incarnation, MemberState::Dead);
+            }
+            expired.into_iter().map(|(id, _)| id).collect()
+        };
+        for id in expired {
+            log_message!(self, "Member {} is dead", id);
+        }
+    }
+}
+
+impl View {
+    fn incarnation(&self, id: &str) -> u64 {
+        self.members.get(id).map_or(0, |e| e.member.incarnation)
+    }
+
+    fn state(&self, id: &str) -> Option<MemberState> {
+        self.members.get(id).map(|e| e.member.state)
+    }
+
+    // Record a change and queue it for dissemination, about 3 * log2(n) messages
+    fn set(&mut self, id: &str, incarnation: u64, state: MemberState) {
+        let n = self.members.len() + 1;
+        let retransmits = 3 * (usize::BITS - n.leading_zeros()) as usize;
+        self.members.insert(
+            id.to_string(),
+            Entry {
+                member: Member {
+                    id: id.to_string(),
+                    incarnation,
+                    state,
+                },
+                changed: Instant::now(),
+                retransmits,
+            },
+        );
+    }
+}
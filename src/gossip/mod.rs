@@ -0,0 +1,9 @@
+use super::*;
+pub mod gossip;
+
+// members asked to probe a target for us when it misses a direct probe
+const INDIRECT_PROBES: usize = 2;
+// most updates carried on a single message
+const MAX_PIGGYBACK: usize = 8;
+// probe intervals a member may stay suspect before it is declared dead
+const SUSPECT_ROUNDS: u32 = 5;
@@ -1,10 +1,240 @@
 use crate::M;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
+use std::fmt;
 
-pub fn hash(input: &str) -> u32 {
+const ID_BYTES: usize = 20;
+pub const MAX_ID_BITS: usize = ID_BYTES * 8;
+
+// A position on the ring, an unsigned integer of up to 160 bits (M of them are used),
+// stored big-endian so the derived ordering is the numeric one
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ChordId([u8; ID_BYTES]);
+
+impl ChordId {
+    pub fn from_be_bytes(bytes: [u8; ID_BYTES]) -> Self {
+        ChordId(bytes).masked()
+    }
+
+    // 2^exp mod 2^M
+    pub fn pow2(exp: usize) -> Self {
+        let mut bytes = [0u8; ID_BYTES];
+        if exp < MAX_ID_BITS {
+            bytes[ID_BYTES - 1 - exp / 8] = 1 << (exp % 8);
+        }
+        ChordId::from_be_bytes(bytes)
+    }
+
+    // (self + other) mod 2^M
+    pub fn wrapping_add(self, other: ChordId) -> Self {
+        let mut bytes = [0u8; ID_BYTES];
+        let mut carry = 0u16;
+        for i in (0..ID_BYTES).rev() {
+            let sum = self.0[i] as u16 + other.0[i] as u16 + carry;
+            bytes[i] = sum as u8;
+            carry = sum >> 8;
+        }
+        ChordId::from_be_bytes(bytes)
+    }
+
+    // (self - other) mod 2^M, the clockwise distance from other to self
+    pub fn wrapping_sub(self, other: ChordId) -> Self {
+        let mut bytes = [0u8; ID_BYTES];
+        let mut borrow = 0i16;
+        for i in (0..ID_BYTES).rev() {
+            let mut diff = self.0[i] as i16 - other.0[i] as i16 - borrow;
+            borrow = 0;
+            if diff < 0 {
+                diff += 256;
+                borrow = 1;
+            }
+            bytes[i] = diff as u8;
+        }
+        ChordId::from_be_bytes(bytes)
+    }
+
+    pub fn to_hex(self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != ID_BYTES * 2 {
+            return None;
+        }
+        let mut bytes = [0u8; ID_BYTES];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(ChordId::from_be_bytes(bytes))
+    }
+
+    // keep only the low M bits
+    fn masked(mut self) -> Self {
+        let m = (*M).min(MAX_ID_BITS);
+        let clear = MAX_ID_BITS - m;
+        for i in 0..clear / 8 {
+            self.0[i] = 0;
+        }
+        if !clear.is_multiple_of(8) {
+            self.0[clear / 8] &= 0xff >> (clear % 8);
+        }
+        self
+    }
+}
+
+impl fmt::Display for ChordId {
+    // decimal, so small rings read the same as plain integers on the dashboards
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut digits = Vec::new();
+        let mut value = self.0;
+        while value.iter().any(|b| *b != 0) {
+            let mut rem = 0u16;
+            for byte in value.iter_mut() {
+                let cur = (rem << 8) | *byte as u16;
+                *byte = (cur / 10) as u8;
+                rem = cur % 10;
+            }
+            digits.push(b'0' + rem as u8);
+        }
+        if digits.is_empty() {
+            digits.push(b'0');
+        }
+        digits.reverse();
+        write!(f, "{}", String::from_utf8(digits).unwrap())
+    }
+}
+
+impl fmt::Debug for ChordId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::str::FromStr for ChordId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(format!("invalid chord id '{}'", s));
+        }
+        let mut bytes = [0u8; ID_BYTES];
+        for c in s.bytes() {
+            let mut carry = (c - b'0') as u16;
+            for byte in bytes.iter_mut().rev() {
+                let cur = *byte as u16 * 10 + carry;
+                *byte = cur as u8;
+                carry = cur >> 8;
+            }
+            if carry != 0 {
+                return Err(format!(
+                    "chord id '{}' is wider than {} bits",
+                    s, MAX_ID_BITS
+                ));
+            }
+        }
+        Ok(ChordId::from_be_bytes(bytes))
+    }
+}
+
+// Serialized as a decimal string, since JSON numbers can't hold 160 bits
+impl Serialize for ChordId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ChordId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+pub fn hash(input: &str) -> ChordId {
     let mut hasher = Sha256::new();
     hasher.update(input);
     let hash = hasher.finalize();
-    let hash_value = u64::from_be_bytes(hash[0..8].try_into().unwrap());
-    (hash_value % 2_u64.pow(*M as u32) as u64) as u32
+    ChordId::from_be_bytes(hash[0..ID_BYTES].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> ChordId {
+        n.to_string().parse().unwrap()
+    }
+
+    fn ids() -> Vec<ChordId> {
+        (0..40).map(|i| hash(&format!("key{}", i))).collect()
+    }
+
+    #[test]
+    fn arithmetic_wraps_at_m_bits() {
+        let zero = ChordId::default();
+        let last = ChordId::from_be_bytes([0xff; ID_BYTES]);
+        assert_eq!(id(3).wrapping_add(id(4)), id(7));
+        assert_eq!(id(7).wrapping_sub(id(4)), id(3));
+        assert_eq!(last.wrapping_add(id(1)), zero);
+        assert_eq!(zero.wrapping_sub(id(1)), last);
+        assert_eq!(id(2).wrapping_sub(id(5)), last.wrapping_sub(id(2)));
+        for a in ids() {
+            for b in ids() {
+                assert_eq!(a.wrapping_add(b).wrapping_sub(b), a);
+            }
+        }
+    }
+
+    #[test]
+    fn ids_are_masked_to_m_bits() {
+        let half = ChordId::pow2(*M - 1);
+        assert_eq!(half.wrapping_add(half), ChordId::default());
+        assert_eq!(ChordId::pow2(*M), ChordId::default());
+        assert_eq!(ChordId::pow2(MAX_ID_BITS), ChordId::default());
+        let last = ChordId::from_be_bytes([0xff; ID_BYTES]);
+        assert_eq!(last.wrapping_sub(half), half.wrapping_sub(id(1)));
+        let bits = last.to_hex().chars().filter(|c| *c != '0').count();
+        assert_eq!(bits, (*M).min(MAX_ID_BITS).div_ceil(4));
+    }
+
+    #[test]
+    fn pow2_sets_one_bit() {
+        assert_eq!(ChordId::pow2(0), id(1));
+        assert_eq!(ChordId::pow2(1), id(2));
+        assert_eq!(ChordId::pow2(3), id(8));
+        assert_eq!(
+            ChordId::pow2(2).wrapping_add(ChordId::pow2(2)),
+            ChordId::pow2(3)
+        );
+    }
+
+    #[test]
+    fn decimal_round_trips() {
+        assert_eq!(id(0).to_string(), "0");
+        assert_eq!(id(19).to_string(), "19");
+        for h in ids() {
+            assert_eq!(h.to_string().parse::<ChordId>().unwrap(), h);
+            assert_eq!(
+                serde_json::from_value::<ChordId>(serde_json::json!(h)).unwrap(),
+                h
+            );
+        }
+        assert!("".parse::<ChordId>().is_err());
+        assert!("1a".parse::<ChordId>().is_err());
+        assert!("-1".parse::<ChordId>().is_err());
+        assert!("9".repeat(60).parse::<ChordId>().is_err());
+    }
+
+    // the sqlite store orders and compares ids by their hex text
+    #[test]
+    fn hex_orders_like_ids() {
+        for a in ids() {
+            assert_eq!(ChordId::from_hex(&a.to_hex()), Some(a));
+            for b in ids() {
+                assert_eq!(a.cmp(&b), a.to_hex().cmp(&b.to_hex()));
+            }
+        }
+        assert_eq!(ChordId::from_hex("12"), None);
+        assert_eq!(ChordId::from_hex(&"zz".repeat(ID_BYTES)), None);
+    }
 }
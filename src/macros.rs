@@ -30,70 +30,66 @@ macro_rules! log_message {
 #[macro_export]
 macro_rules! send_post_request {
     ($url:expr, $message:expr) => {
-        send_post_request!($url, $message, 3) // Default to 3 retries
+        send_post_request!(
+            $url,
+            $message,
+            $crate::peer_client::PEER_CLIENT.retry.max_retries
+        )
+    };
+    ($url:expr, $message:expr, $max_retries:expr) => {
+        send_post_request!(
+            $url,
+            $message,
+            $max_retries,
+            $crate::peer_client::PEER_CLIENT.request_timeout
+        )
+    };
+    ($url:expr, $message:expr, $max_retries:expr, $timeout:expr) => {
+        $crate::peer_client::PEER_CLIENT
+            .post($url, &$message, $max_retries, $timeout)
+            .await
     };
-    ($url:expr, $message:expr, $max_retries:expr) => {{
-        let client = reqwest::Client::new();
-        let json_body = serde_json::to_string(&$message).unwrap();
-        let mut attempts = 0;
-        let result = loop {
-            match client
-                .post($url)
-                .header("Content-Type", "application/json")
-                .body(json_body.clone())
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    break Ok(response);
-                }
-                Err(e) => {
-                    attempts += 1;
-                    if attempts < $max_retries {
-                        // Exponential backoff: wait 2^attempts * 100ms
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            100 * (2_u64.pow(attempts as u32)),
-                        ))
-                        .await;
-                        continue;
-                    } else {
-                        break Err(e);
-                    }
-                }
-            }
-        };
-        result
-    }};
 }
 
 #[macro_export]
 macro_rules! send_get_request {
     ($url:expr) => {
-        send_get_request!($url, 3) // Default to 3 retries
+        send_get_request!($url, $crate::peer_client::PEER_CLIENT.retry.max_retries)
+    };
+    ($url:expr, $max_retries:expr) => {
+        send_get_request!(
+            $url,
+            $max_retries,
+            $crate::peer_client::PEER_CLIENT.request_timeout
+        )
+    };
+    ($url:expr, $max_retries:expr, $timeout:expr) => {
+        $crate::peer_client::PEER_CLIENT
+            .get($url, $max_retries, $timeout)
+            .await
+    };
+}
+
+#[macro_export]
+macro_rules! send_message {
+    ($peer:expr, $message:expr) => {
+        send_message!(
+            $peer,
+            $message,
+            $crate::peer_client::PEER_CLIENT.retry.max_retries
+        )
+    };
+    ($peer:expr, $message:expr, $max_retries:expr) => {
+        send_message!(
+            $peer,
+            $message,
+            $max_retries,
+            $crate::peer_client::PEER_CLIENT.request_timeout
+        )
+    };
+    ($peer:expr, $message:expr, $max_retries:expr, $timeout:expr) => {
+        $crate::transport::PEER_TRANSPORT
+            .send($peer, &$message, $max_retries, $timeout)
+            .await
     };
-    ($url:expr, $max_retries:expr) => {{
-        let client = reqwest::Client::new();
-        let mut attempts = 0;
-        let result = loop {
-            match client.get($url).send().await {
-                Ok(response) => {
-                    break Ok(response);
-                }
-                Err(e) => {
-                    attempts += 1;
-                    if attempts < $max_retries {
-                        // Exponential backoff: wait 2^attempts * 100ms
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            100 * (2_u64.pow(attempts as u32)),
-                        ))
-                        .await;
-                        continue;
-                    } else {
-                        break Err(e);
-                    }
-                }
-            }
-        };
-        result
-    }};
 }
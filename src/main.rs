@@ -1,9 +1,14 @@
 #![allow(unused_must_use)] // this is for the macros to ignore the Result type
+#![allow(clippy::module_inception)]
 mod chord_server;
 mod data_misc;
+mod gossip;
 pub mod hash;
 mod macros;
 mod node;
+mod peer_client;
+mod rpc;
+mod transport;
 
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use chord_server::chord::*;
@@ -27,6 +32,68 @@ lazy_static::lazy_static! {
         }
     };
     static ref DEFAULT_CHANNEL_SIZE: usize = dotenv::var("DEFAULT_CHANNEL_SIZE").unwrap().parse().unwrap();
+    static ref STORE: String = dotenv::var("STORE").unwrap_or_else(|_| String::from("sqlite"));
+    static ref DATA_DIR: Option<String> = dotenv::var("DATA_DIR").ok().filter(|d| !d.is_empty());
+    static ref VNODES: usize = dotenv::var("VNODES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(1);
+    // comma separated ip:port list of ring members a node can join through
+    static ref SEEDS: Vec<String> = dotenv::var("SEEDS")
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    // milliseconds between gossip probes
+    static ref GOSSIP_INTERVAL: u64 = dotenv::var("GOSSIP_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    // read-only messages (lookups) a node handles at the same time
+    static ref MAX_CONCURRENT_MESSAGES: usize = dotenv::var("MAX_CONCURRENT_MESSAGES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(64);
+    // talking to other nodes: milliseconds to connect and to complete a request,
+    // attempts per request and the base of the jittered backoff between them
+    static ref PEER_CONNECT_TIMEOUT: u64 = dotenv::var("PEER_CONNECT_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    static ref PEER_REQUEST_TIMEOUT: u64 = dotenv::var("PEER_REQUEST_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3000);
+    // for requests carrying a batch of keys (uploads, replicas, transfers), which take longer
+    static ref PEER_BULK_TIMEOUT: u64 = dotenv::var("PEER_BULK_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30000);
+    static ref PEER_RETRIES: u32 = dotenv::var("PEER_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(3);
+    static ref PEER_BACKOFF: u64 = dotenv::var("PEER_BACKOFF")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+    // http (JSON on /msg and /rpc) or tcp (binary frames) between nodes
+    static ref TRANSPORT: String = dotenv::var("TRANSPORT").unwrap_or_else(|_| String::from("http"));
+    // the tcp transport listens this far above a node's HTTP port
+    static ref TCP_PORT_OFFSET: u16 = dotenv::var("TCP_PORT_OFFSET")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    static ref REQUEST_TIMEOUT: u64 = dotenv::var("REQUEST_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
 }
 
 fn get_tailscale_ip() -> std::io::Result<String> {
@@ -44,10 +111,7 @@ fn get_tailscale_ip() -> std::io::Result<String> {
             .to_string();
         Ok(ip)
     } else {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Failed to get Tailscale IP",
-        ))
+        Err(std::io::Error::other("Failed to get Tailscale IP"))
     }
 }
 
@@ -55,8 +119,14 @@ fn get_tailscale_ip() -> std::io::Result<String> {
 async fn main() -> std::io::Result<()> {
     let args: Vec<String> = std::env::args().collect();
 
+    if *M == 0 || *M > hash::MAX_ID_BITS {
+        println!("M must be between 1 and {}", hash::MAX_ID_BITS);
+        std::process::exit(1);
+    }
+
     match args.get(2).map(|s| s.as_str()) {
         Some("chord") => {
+            envelope::set_sender(format!("{}:{}", *IP, *PORT));
             let chord_server = Arc::new(ChordRing::new());
             let chord_server_clone = Arc::clone(&chord_server);
 
@@ -71,33 +141,56 @@ async fn main() -> std::io::Result<()> {
         }
         Some("node") => {
             let node_port = args[3].parse::<u16>().unwrap_or(*PORT);
-            let node = Node::new(Some(node_port));
-            let node_clone = node.clone();
+            envelope::set_sender(format!("{}:{}", *IP, node_port));
+            let nodes = Node::new_host(Some(node_port));
+            let nodes_clone = nodes.clone();
+            if transport::PEER_TRANSPORT.is_tcp() {
+                helper::start_tcp_server(nodes.clone()).await?;
+            }
 
             let server_handle = std::thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async {
-                    helper::run_server(node_clone).await.unwrap();
+                    helper::run_server(nodes_clone).await.unwrap();
                     // println!("Starting node {}:{}", *IP, node_port);
                 });
             });
 
-            let mut retries = 5;
-            while retries > 0 {
-                // match node.req_known_node(format!("{}:{}", *IP, *PORT)).await {
-                match node.req_known_node(format!("{}:{}", args[4], *PORT)).await {
-                    Ok(()) => break,
-                    Err(_) => {
-                        retries -= 1;
-                        if retries > 0 {
-                            log_message!(node, "Retrying to connect to known node");
-                            std::thread::sleep(std::time::Duration::from_secs(1));
-                        } else {
-                            println!("Failed to connect to known node");
-                            std::process::exit(1);
+            // args: <ring port> node <port> [<ring ip> | -] [seed ip:port ...]
+            // "-" runs without the ChordRing, joining only through the seeds
+            *CHORD_RING.lock().await = match args.get(4).map(|s| s.as_str()) {
+                None | Some("-") => None,
+                Some(ring_ip) => Some(format!("{}:{}", ring_ip, *PORT)),
+            };
+            *SEED_NODES.lock().await = args.iter().skip(5).chain(SEEDS.iter()).cloned().collect();
+
+            // Virtual node 0 joins (or starts) the ring first, the others join through it
+            // once it is in
+            for (i, node) in nodes.iter().enumerate() {
+                let mut retries = 5;
+                while retries > 0 {
+                    // match node.req_known_node(format!("{}:{}", *IP, *PORT)).await {
+                    match node.join().await {
+                        Ok(()) => break,
+                        Err(_) => {
+                            retries -= 1;
+                            if retries > 0 {
+                                log_message!(node, "Retrying to connect to known node");
+                                std::thread::sleep(std::time::Duration::from_secs(1));
+                            } else {
+                                println!("Failed to reach a seed node or the ChordRing");
+                                std::process::exit(1);
+                            }
                         }
                     }
                 }
+                if i == 0
+                    && nodes.len() > 1
+                    && !node.wait_joined(std::time::Duration::from_secs(30)).await
+                {
+                    println!("Virtual node 0 did not get into the ring, the others can't join");
+                    std::process::exit(1);
+                }
             }
 
             server_handle.join().unwrap();
@@ -1,15 +1,18 @@
-use super::{hash::*, M, *};
+use super::{M, *};
 use std::fmt::Debug;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FingerEntry {
-    pub start: u32,
+    pub start: ChordId,
     pub id: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct FingerTable {
     pub entries: Vec<FingerEntry>,
+    // entry fix_fingers refreshes next
+    #[serde(skip)]
+    next: usize,
 }
 
 impl Debug for FingerTable {
@@ -28,10 +31,10 @@ impl FingerTable {
         let mut entries = Vec::with_capacity(*M);
         for i in 0..*M {
             // the start might wrap around the ring
-            let start = (hash(&id) + 2u32.pow(i as u32)) % 2u32.pow(*M as u32);
+            let start = hash(&id).wrapping_add(ChordId::pow2(i));
             entries.push(FingerEntry { start, id: None });
         }
-        FingerTable { entries }
+        FingerTable { entries, next: 0 }
     }
 
     pub fn clear(&mut self) {
@@ -40,27 +43,51 @@ impl FingerTable {
         }
     }
 
-    pub fn get_first_entry(&self) -> u32 {
+    pub fn get_first_entry(&self) -> ChordId {
         self.entries[0].start
     }
 
-    pub fn get_next_entry(&self, index: u32) -> Option<u32> {
-        let current_pos = self.entries.iter().position(|entry| entry.start == index)?;
-        let next_pos = current_pos + 1;
-        if next_pos >= self.entries.len() {
-            None
-        } else {
-            Some(self.entries[next_pos].start)
+    // Start of the entry to refresh on this tick, walking the table round-robin
+    pub fn next_to_fix(&mut self) -> ChordId {
+        let start = self.entries[self.next].start;
+        self.next = (self.next + 1) % self.entries.len();
+        start
+    }
+
+    // The finger that most closely precedes `key` on the ring, strictly between `node` and `key`
+    pub fn closest_preceding_finger(&self, node: ChordId, key: ChordId) -> Option<&String> {
+        self.entries.iter().rev().find_map(|entry| {
+            let id = entry.id.as_ref()?;
+            let finger = hash(id);
+            (finger != key && is_between(node, finger, key)).then_some(id)
+        })
+    }
+
+    // Forget a node that stopped answering, fix_fingers fills its entries in again
+    pub fn remove_node(&mut self, id: &str) -> bool {
+        let mut removed = false;
+        for entry in self.entries.iter_mut() {
+            if entry.id.as_deref() == Some(id) {
+                entry.id = None;
+                removed = true;
+            }
         }
+        removed
     }
 
-    pub fn update_entry(&mut self, index: u32, id: String) -> bool {
-        if let Some(entry) = self.entries.iter_mut().find(|e| e.start == index) {
-            let changed = entry.id.as_ref() != Some(&id);
-            entry.id = Some(id);
-            changed
-        } else {
-            false
+    // `id` is the successor of `index`, so it is also the successor of every later start
+    // up to its own position; those entries are filled in as well
+    pub fn update_entry(&mut self, index: ChordId, id: String) -> bool {
+        let position = hash(&id);
+        let mut changed = false;
+        for entry in self.entries.iter_mut() {
+            let covered = entry.start == index
+                || (index != position && is_between(index, entry.start, position));
+            if covered && entry.id.as_ref() != Some(&id) {
+                entry.id = Some(id.clone());
+                changed = true;
+            }
         }
+        changed
     }
 }
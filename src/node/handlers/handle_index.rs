@@ -3,25 +3,13 @@ use crate::node::node::CHORD_RING; // Add this import at the top
 pub async fn handle_index(data: web::Data<Node>) -> impl Responder {
     let node_state = data.node_state.lock().await;
     let logs = data.logs.lock().await;
-    let conn = data.db.lock().await;
-    let mut stmt = conn
-        .prepare("SELECT * FROM data ORDER BY hash ASC")
-        .unwrap();
-
-    let data_iter = stmt
-        .query_map([], |row| {
-            Ok((
-                row.get::<_, i32>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-            ))
-        })
+    let data_vec = data
+        .select_data(
+            node_state.predecessor.as_ref().map(|p| hash(p)),
+            Some(hash(&node_state.id)),
+        )
+        .await
         .unwrap();
-
-    let mut data_vec = Vec::new();
-    for data in data_iter {
-        data_vec.push(data.unwrap());
-    }
     // Read the HTML template
     let mut html = std::fs::read_to_string(HTML_PATH).expect("Failed to read HTML template");
 
@@ -30,7 +18,21 @@ pub async fn handle_index(data: web::Data<Node>) -> impl Responder {
         "{{node_id}}",
         format!("{} [{}]", node_state.id, hash(&node_state.id)).as_str(),
     );
-    html = html.replace("{HOME_URL}", &format!("http://{}", CHORD_RING.lock().await));
+    let base_path = match data.vnode {
+        0 => String::new(),
+        vnode => format!("/v/{}", vnode),
+    };
+    html = html.replace("{BASE_PATH}", &base_path);
+    html = html.replace(
+        "{PROTOCOL_VERSION}",
+        &envelope::PROTOCOL_VERSION.to_string(),
+    );
+    let ring = CHORD_RING.lock().await.clone();
+    let home_url = match ring {
+        Some(chord_ring) => format!("http://{}", chord_ring),
+        None => format!("{}/", base_path),
+    };
+    html = html.replace("{HOME_URL}", &home_url);
     html = html.replace(
         "{{predecessor}}",
         format!(
@@ -44,36 +46,33 @@ pub async fn handle_index(data: web::Data<Node>) -> impl Responder {
                 .predecessor
                 .as_ref()
                 .map(|id| hash(id))
-                .unwrap_or(0)
+                .unwrap_or_default()
         )
         .as_str(),
     );
     html = html.replace(
         "{{successor}}",
-        format!(
-            "{}",
-            node_state
-                .successor
-                .entries
-                .iter()
-                .enumerate()
-                .map(|(i, entry)| {
-                    if let Some(id) = entry {
-                        format!(
-                            "<li>[{i}]: <span class=\"font-semibold\"><a href=\"http://{0}\">{0} [{1}]</a></span></li>",
-                            id,
-                            hash(id)
-                        )
-                    } else {
-                        format!(
-                            "<li>[{i}]: <span class=\"font-semibold\">None [0]</span></li>"
-                        )
-                    }
-                })
-                .collect::<Vec<String>>()
-                .join("")
-        )
-        .as_str(),
+        node_state
+            .successor
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                if let Some(id) = entry {
+                    format!(
+                        "<li>[{i}]: <span class=\"font-semibold\"><a href=\"http://{0}\">{0} [{1}]</a></span></li>",
+                        id,
+                        hash(id)
+                    )
+                } else {
+                    format!(
+                        "<li>[{i}]: <span class=\"font-semibold\">None [0]</span></li>"
+                    )
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("")
+            .as_str(),
     );
 
     let log_html = logs
@@ -122,10 +121,12 @@ pub async fn handle_index(data: web::Data<Node>) -> impl Responder {
 
     let data_count = data_vec.len();
     html = html.replace("{data_count}", &data_count.to_string());
+    let replica_count = data.replicas.lock().await.count().unwrap();
+    html = html.replace("{replica_count}", &replica_count.to_string());
 
     let data_html = data_vec
         .iter()
-        .map(|data| format!("<li>[{}] {}: {}</li>", data.0, data.1, data.2))
+        .map(|d| format!("<li>[{}] {}: {}</li>", hash(&d.key), d.key, d.value))
         .collect::<Vec<String>>()
         .join("");
     html = html.replace(
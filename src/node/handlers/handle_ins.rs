@@ -1,59 +1,137 @@
 use super::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct InsertParams {
+    // nodes the rows went through before this one
+    #[serde(default)]
+    pub hops: usize,
+    // what to do with keys that are already stored, fail the batch by default
+    pub mode: Option<WriteMode>,
+}
+
+// Store the rows in our range and pass every other one on towards the node responsible
+// for it, answering with what each of those nodes stored
 pub async fn handle_insert(
     data: web::Data<Node>,
+    params: web::Query<InsertParams>,
     data_to_ins: web::Json<Vec<Data>>,
 ) -> impl Responder {
-    let node_state = data.node_state.lock().await;
-    let node_hash = hash(&node_state.id);
-    let prev_hash = node_state
-        .predecessor
-        .as_ref()
-        .map(|id| hash(id))
-        .unwrap_or(node_hash);
-
-    let mut local_data = Vec::new();
-    let mut forward_data = Vec::new();
+    let node_state = data.snapshot().await;
+    let mode = params.mode.unwrap_or(WriteMode::InsertOnly);
 
     log_message!(
         data,
-        "Handling insert request for {} data items",
-        data_to_ins.len()
+        "Handling insert request for {} data items ({:?})",
+        data_to_ins.len(),
+        mode
     );
 
-    for item in data_to_ins.iter() {
-        let data_hash = hash(&item.key);
-
-        if is_between(prev_hash, data_hash, node_hash) {
-            local_data.push(item.clone());
-        } else {
-            forward_data.push(item.clone());
+    // rows for other nodes, grouped by the next hop along with the ones to try after it
+    let mut local_data = Vec::new();
+    let mut forward_data: HashMap<String, (Vec<String>, Vec<Data>)> = HashMap::new();
+    for item in data_to_ins.into_inner() {
+        match route(&node_state, hash(&item.key)) {
+            Route::Local => local_data.push(item),
+            Route::Forward(candidates) => {
+                forward_data
+                    .entry(candidates[0].clone())
+                    .or_insert_with(|| (candidates, Vec::new()))
+                    .1
+                    .push(item);
+            }
         }
     }
 
+    let mut summary = InsertSummary::default();
     if !local_data.is_empty() {
-        log_message!(data, "{} data items belong to this node", local_data.len());
-        match data.insert_batch_data(local_data).await {
-            Ok(_) => {
+        let rows = local_data.len();
+        log_message!(data, "{} data items belong to this node", rows);
+        match data.insert_batch_data(local_data, mode).await {
+            Ok(written) => {
                 log_message!(data, "Data inserted successfully");
-                HttpResponse::Ok().body("Data inserted successfully")
+                summary.merge(InsertSummary::stored(
+                    &node_state.id,
+                    written,
+                    rows - written,
+                ));
             }
             Err(err) => {
                 log_message!(data, "Error inserting data: {}", err);
-                HttpResponse::InternalServerError().body(err.to_string())
+                let conflicts = match &err {
+                    StoreError::Conflict(keys) => keys.clone(),
+                    _ => Vec::new(),
+                };
+                summary.merge(InsertSummary::conflict(
+                    &node_state.id,
+                    rows,
+                    err.to_string(),
+                    conflicts,
+                ));
             }
         }
-    } else if !forward_data.is_empty() {
-        // Forward to successor if data doesn't belong here
-        if let Some(successor) = node_state.successor.get_first() {
-            log_message!(data, "Forwarding data to successor node: {}", successor);
-            send_post_request!(&format!("http://{}/insert", successor), forward_data);
-            log_message!(data, "Data forwarded successfully to successor");
-            HttpResponse::Ok().body("Data forwarded to successor")
-        } else {
-            log_message!(data, "No successor found, cannot forward data");
-            HttpResponse::InternalServerError().body("No successor found")
+    }
+
+    for (candidates, rows) in forward_data.into_values() {
+        if params.hops + 1 >= rpc::MAX_HOPS {
+            summary.merge(InsertSummary::failure(
+                &candidates[0],
+                rows.len(),
+                format!("not delivered after {} hops", params.hops + 1),
+            ));
+            continue;
+        }
+        summary.merge(forward_insert(&data, &candidates, rows, mode, params.hops + 1).await);
+    }
+
+    HttpResponse::Ok().json(summary)
+}
+
+async fn forward_insert(
+    data: &web::Data<Node>,
+    candidates: &[String],
+    rows: Vec<Data>,
+    mode: WriteMode,
+    hops: usize,
+) -> InsertSummary {
+    for node in candidates {
+        log_message!(
+            data,
+            "Forwarding {} data items to node: {}",
+            rows.len(),
+            node
+        );
+        let url = format!(
+            "http://{}/insert?hops={}&mode={}",
+            node,
+            hops,
+            mode.as_str()
+        );
+        let client = &peer_client::PEER_CLIENT;
+        match send_post_request!(&url, rows, client.retry.max_retries, client.bulk_timeout) {
+            Ok(res) => {
+                return match res.json::<InsertSummary>().await {
+                    Ok(summary) => summary,
+                    Err(e) => InsertSummary::failure(node, rows.len(), e.to_string()),
+                };
+            }
+            Err(e) if e.is_connect() => {
+                log_message!(data, "Failed to forward data to node {}: {}", node, e)
+            }
+            // it got the rows and may have stored them, so they don't go to another node
+            Err(e) => {
+                log_message!(data, "Node {} did not answer: {}", node, e);
+                return InsertSummary::failure(
+                    node,
+                    rows.len(),
+                    format!("no answer, the rows may have been stored: {}", e),
+                );
+            }
         }
-    } else {
-        HttpResponse::Ok().body("No data to process")
     }
+    InsertSummary::failure(
+        &candidates[0],
+        rows.len(),
+        "no node on the way to it could be reached".to_string(),
+    )
 }
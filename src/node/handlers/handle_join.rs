@@ -1,12 +1,8 @@
 use super::*;
 pub async fn handle_join(data: web::Data<Node>) -> impl Responder {
-    // send a message to the ChordRing to request known nodes
-    let node_state = data.node_state.lock().await;
-    let node_id = node_state.id.clone();
-    send_post_request!(
-        &format!("http://{}/msg", format!("{}", CHORD_RING.lock().await)),
-        Message::ReqKnownNode { node_id }
-    );
-
-    HttpResponse::Ok().body("Join request sent")
+    // join through the seeds, or ask the ChordRing for a known node
+    match data.join().await {
+        Ok(_) => HttpResponse::Ok().body("Join request sent"),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
 }
@@ -0,0 +1,36 @@
+use super::*;
+pub async fn handle_get_key(
+    data: web::Data<Node>,
+    path: web::Path<String>,
+    params: web::Query<QueryParams>,
+) -> impl Responder {
+    match params.parse() {
+        Ok(query) => data.lookup(path.into_inner(), query).await,
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
+            "status": "error",
+            "message": e
+        })),
+    }
+}
+
+pub async fn handle_put_key(
+    data: web::Data<Node>,
+    path: web::Path<String>,
+    put_req: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let (put_data, mode) = match Data::from_put(&path.into_inner(), &put_req) {
+        Ok(put) => put,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "message": e
+            }));
+        }
+    };
+
+    data.put(put_data, mode).await
+}
+
+pub async fn handle_delete_key(data: web::Data<Node>, path: web::Path<String>) -> impl Responder {
+    data.delete(path.into_inner()).await
+}
@@ -0,0 +1,27 @@
+use super::*;
+pub async fn handle_lookup(
+    data: web::Data<Node>,
+    lookup_req: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let key = match lookup_req.get("key") {
+        Some(k) => k.as_str().unwrap_or_default().to_string(),
+        None => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "message": "Missing key in request"
+            }));
+        }
+    };
+
+    let query = match Query::from_body(&lookup_req) {
+        Ok(query) => query,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "status": "error",
+                "message": e
+            }));
+        }
+    };
+
+    data.lookup(key, query).await
+}
@@ -0,0 +1,4 @@
+use super::*;
+pub async fn handle_members(data: web::Data<Node>) -> impl Responder {
+    HttpResponse::Ok().json(data.gossip.members().await)
+}
@@ -0,0 +1,4 @@
+use super::*;
+pub async fn handle_metrics(data: web::Data<Node>) -> impl Responder {
+    HttpResponse::Ok().json(data.metrics_report())
+}
@@ -1,24 +1,80 @@
 use super::*;
-pub async fn handle_message(data: web::Data<Node>, message: web::Json<Message>) -> impl Responder {
-    match message.0 {
-        Message::Ping => {
-            return HttpResponse::Ok().json(serde_json::json!(Message::Pong));
+use actix_web::http::StatusCode;
+use envelope::{Envelope, EnvelopeError};
+pub async fn handle_message(
+    data: web::Data<Node>,
+    body: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let (status, reply) = match Envelope::from_json(body.into_inner()) {
+        Ok(envelope) => receive_message(&data, envelope).await,
+        Err(e) => reject(&data, e).await,
+    };
+    HttpResponse::build(status).json(reply)
+}
+
+// A message from another node, whichever transport it came in over
+pub async fn receive_message(data: &web::Data<Node>, envelope: Envelope) -> (StatusCode, Message) {
+    if let Err(e) = envelope.check_version() {
+        return reject(data, e).await;
+    }
+    let message = envelope.message;
+    match message {
+        Message::Ping { gossip } => {
+            data.gossip.merge(gossip).await;
+            let gossip = data.gossip.piggyback().await;
+            (StatusCode::OK, Message::Pong { gossip })
+        }
+        Message::PingReq { target, gossip } => {
+            data.gossip.merge(gossip).await;
+            if data.gossip.ping(&target).await {
+                let gossip = data.gossip.piggyback().await;
+                (StatusCode::OK, Message::Pong { gossip })
+            } else {
+                (
+                    StatusCode::BAD_GATEWAY,
+                    Message::ErrorMessage {
+                        error: format!("{} did not answer", target),
+                    },
+                )
+            }
         }
-        _ => {
-            let tx = data.tx.clone();
-            if let Err(err) = tx.send(message.into_inner()).await {
+        _ => match data.tx.try_send(message) {
+            Ok(()) => (
+                StatusCode::OK,
+                Message::Success {
+                    message: "Message sent successfully".to_string(),
+                },
+            ),
+            // back-pressure: the sender backs off or tries another node instead of piling up here
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                data.metrics.rejected();
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Message::ErrorMessage {
+                        error: "Message queue is full".to_string(),
+                    },
+                )
+            }
+            Err(err) => {
                 log_message!(data, "ERROR sending message: {}", err.to_string());
 
-                return HttpResponse::InternalServerError().json(serde_json::json!(
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
                     Message::ErrorMessage {
                         error: err.to_string(),
-                    }
-                ));
+                    },
+                )
             }
-
-            HttpResponse::Ok().json(serde_json::json!(Message::Success {
-                message: "Message sent successfully".to_string(),
-            }))
-        }
+        },
     }
 }
+
+async fn reject(data: &web::Data<Node>, error: EnvelopeError) -> (StatusCode, Message) {
+    log_message!(data, "Rejected a message: {}", error);
+    (
+        StatusCode::from_u16(error.status()).unwrap_or(StatusCode::BAD_REQUEST),
+        Message::ErrorMessage {
+            error: error.to_string(),
+        },
+    )
+}
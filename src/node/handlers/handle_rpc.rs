@@ -0,0 +1,65 @@
+use super::*;
+use actix_web::http::StatusCode;
+use envelope::Envelope;
+
+// Ring maintenance calls from other nodes, answered on the same request
+pub async fn handle_rpc(
+    data: web::Data<Node>,
+    body: web::Json<serde_json::Value>,
+) -> impl Responder {
+    let request = match Envelope::<RpcRequest>::from_json(body.into_inner()) {
+        Ok(envelope) => envelope.message,
+        Err(e) => {
+            log_message!(data, "Rejected an rpc: {}", e);
+            return HttpResponse::build(
+                StatusCode::from_u16(e.status()).unwrap_or(StatusCode::BAD_REQUEST),
+            )
+            .json(RpcResponse::Error(RpcError::Failed(e.to_string())));
+        }
+    };
+    match serve(data.get_ref(), request).await {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => {
+            log_message!(data, "Failed to serve rpc: {}", e);
+            HttpResponse::InternalServerError().json(RpcResponse::Error(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use envelope::MIN_PROTOCOL_VERSION;
+
+    fn node() -> web::Data<Node> {
+        let store = || -> SharedStore { Arc::new(Mutex::new(Box::new(MemoryStore::new()))) };
+        let gossip = Membership::new(Arc::new(Mutex::new(Vec::new())));
+        Node::new("127.0.0.1:9201", 0, store(), store(), gossip)
+    }
+
+    #[actix_web::test]
+    async fn rpcs_outside_the_supported_versions_are_refused() {
+        let app = init_service(
+            actix_web::App::new()
+                .app_data(node())
+                .route("/rpc", web::post().to(handle_rpc)),
+        )
+        .await;
+        let post = |body: serde_json::Value| TestRequest::post().uri("/rpc").set_json(body);
+
+        let mut envelope = Envelope::new(RpcRequest::GetPredecessor);
+        let response: RpcResponse =
+            call_and_read_body_json(&app, post(serde_json::json!(envelope)).to_request()).await;
+        assert!(matches!(response, RpcResponse::Predecessor(Some(_))));
+
+        envelope.version = MIN_PROTOCOL_VERSION - 1;
+        let response = call_service(&app, post(serde_json::json!(envelope)).to_request()).await;
+        assert_eq!(response.status(), 426);
+
+        // a bare request from before rpcs had an envelope
+        let bare = serde_json::json!(RpcRequest::GetPredecessor);
+        let response = call_service(&app, post(bare).to_request()).await;
+        assert_eq!(response.status(), 426);
+    }
+}
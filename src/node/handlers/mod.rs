@@ -2,13 +2,23 @@ use super::*;
 pub mod handle_index;
 pub mod handle_ins;
 pub mod handle_join;
+pub mod handle_kv;
 pub mod handle_leave;
+pub mod handle_lookup;
+pub mod handle_members;
+pub mod handle_metrics;
 pub mod handle_msg;
+pub mod handle_rpc;
 pub mod handle_succ_pred;
 
 pub use handle_index::*;
 pub use handle_ins::*;
 pub use handle_join::*;
+pub use handle_kv::*;
 pub use handle_leave::*;
+pub use handle_lookup::*;
+pub use handle_members::*;
+pub use handle_metrics::*;
 pub use handle_msg::*;
+pub use handle_rpc::*;
 pub use handle_succ_pred::*;
@@ -1,5 +1,7 @@
 use super::*;
-pub fn is_between(start: u32, key: u32, end: u32) -> bool {
+use tokio::net::TcpListener;
+
+pub fn is_between(start: ChordId, key: ChordId, end: ChordId) -> bool {
     if start < end {
         key > start && key <= end
     } else {
@@ -7,14 +9,29 @@ pub fn is_between(start: u32, key: u32, end: u32) -> bool {
     }
 }
 
-pub fn in_mem_db() -> Connection {
-    let conn = Connection::open_in_memory().unwrap();
+// Opens data/<ip>_<port>.db under DATA_DIR so keys survive a restart,
+// or an in-memory database when DATA_DIR is not set.
+// Either way every call for one node gets its own connection to the same database,
+// so the data and replicas tables of a node always live side by side.
+pub fn open_db(node_id: &str) -> Connection {
+    let name = node_id.replace([':', '/'], "_");
+    let conn = match DATA_DIR.as_ref() {
+        Some(dir) => {
+            std::fs::create_dir_all(dir).expect("Failed to create data directory");
+            let path = format!("{}/{}.db", dir, name);
+            Connection::open(&path).expect("Failed to open database file")
+        }
+        // a named memdb database lives as long as one of its connections is open
+        None => Connection::open(format!("file:/{}?vfs=memdb", name))
+            .expect("Failed to open in-memory database"),
+    };
 
     #[cfg(debug_assertions)]
     println!("Database connection established.");
 
     let schema = std::fs::read_to_string("./misc/schema.sql").expect("Failed to read schema.sql");
-    conn.execute(&schema, []).expect("Failed to execute schema");
+    conn.execute_batch(&schema)
+        .expect("Failed to execute schema");
 
     #[cfg(debug_assertions)]
     {
@@ -36,62 +53,125 @@ pub fn in_mem_db() -> Connection {
     conn
 }
 
+// Keys in this virtual node's range (predecessor, self]; the store itself is shared
 pub async fn get_data(data: web::Data<Node>) -> impl Responder {
-    let conn = data.db.lock().await;
-    let mut stmt = conn.prepare("SELECT * FROM data").unwrap();
-    let data_iter = stmt
-        .query_map([], |row| {
-            // println!("Row: {:?}", row);
-            Ok(Data::new(
-                row.get::<_, String>(1).unwrap().as_str(),
-                row.get::<_, String>(2).unwrap().as_str(),
-            ))
-        })
-        .unwrap();
-
-    let mut data_vec = Vec::new();
-    for data in data_iter {
-        data_vec.push(data.unwrap());
+    let (start, end) = {
+        let ns = data.node_state.lock().await;
+        (ns.predecessor.as_ref().map(|p| hash(p)), hash(&ns.id))
+    };
+    match data.select_data(start, Some(end)).await {
+        Ok(data_vec) => HttpResponse::Ok().json(data_vec),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
     }
+}
 
-    HttpResponse::Ok().json(data_vec)
+// Routes every virtual node serves, either at the root or under its /v/<n> scope
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::get().to(handle_index))
+        .route("/data", web::get().to(get_data))
+        .route("/leave", web::post().to(handle_leave))
+        .route("/join", web::post().to(handle_join))
+        .route("/insert", web::post().to(handle_insert))
+        .route("/lookup", web::post().to(handle_lookup))
+        .route("/kv/{key}", web::get().to(handle_get_key))
+        .route("/kv/{key}", web::put().to(handle_put_key))
+        .route("/kv/{key}", web::delete().to(handle_delete_key))
+        .route("/successors", web::get().to(handle_successors))
+        .route("/predecessor", web::get().to(handle_predecessor))
+        .route("/members", web::get().to(handle_members))
+        .route("/metrics", web::get().to(handle_metrics))
+        .route("/rpc", web::post().to(handle_rpc))
+        .route(
+            "/msg",
+            web::post().to(
+                move |data: web::Data<Node>, body: web::Json<serde_json::Value>| {
+                    handle_message(data, body)
+                },
+            ),
+        );
 }
 
-pub async fn run_server(app_state: web::Data<Node>) -> std::io::Result<()> {
-    let id = app_state.node_state.lock().await.id.clone();
-    #[cfg(debug_assertions)]
-    println!("(Node)Server running at http://{}", id.clone());
+pub async fn run_server(nodes: Vec<web::Data<Node>>) -> std::io::Result<()> {
+    for app_state in nodes.iter() {
+        let id = app_state.node_state.lock().await.id.clone();
+        #[cfg(debug_assertions)]
+        println!("(Node)Server running at http://{}", id.clone());
 
-    app_state.node_state.lock().await.predecessor = Some(id.clone());
-    app_state
-        .node_state
-        .lock()
-        .await
-        .successor
-        .insert_first(id.clone());
+        app_state.node_state.lock().await.predecessor = Some(id.clone());
+        app_state
+            .node_state
+            .lock()
+            .await
+            .successor
+            .insert_first(id.clone());
+    }
 
+    // Virtual node 0 owns the plain ip:port the whole process listens on
     let bind_address = {
-        let node_state = app_state.node_state.lock().await;
-        node_state.id.clone()
+        let node_state = nodes[0].node_state.lock().await;
+        host_of(&node_state.id).to_string()
     };
     HttpServer::new(move || {
-        App::new()
-            .app_data(app_state.clone())
-            .route("/", web::get().to(handle_index))
-            .route("/data", web::get().to(get_data))
-            .route("/leave", web::post().to(handle_leave))
-            .route("/join", web::post().to(handle_join))
-            .route("/insert", web::post().to(handle_insert))
-            .route("/successors", web::get().to(handle_successors))
-            .route("/predecessor", web::get().to(handle_predecessor))
-            .route(
-                "/msg",
-                web::post().to(move |data: web::Data<Node>, message: web::Json<Message>| {
-                    handle_message(data, message)
-                }),
-            )
+        let mut app = App::new().app_data(nodes[0].clone());
+        for node in nodes.iter().skip(1) {
+            app = app.service(
+                web::scope(&format!("/v/{}", node.vnode))
+                    .app_data(node.clone())
+                    .route("", web::get().to(handle_index))
+                    .configure(routes),
+            );
+        }
+        app.configure(routes)
     })
     .bind(bind_address.clone())?
     .run()
     .await
 }
+
+// The binary transport, TCP_PORT_OFFSET above the HTTP port. It is bound before the virtual
+// nodes join, since their new neighbours talk back to them right away.
+pub async fn start_tcp_server(nodes: Vec<web::Data<Node>>) -> std::io::Result<()> {
+    let bind_address = tcp_addr(&nodes[0].node_state.lock().await.id);
+    let listener = TcpListener::bind(&bind_address).await?;
+    #[cfg(debug_assertions)]
+    println!("(Node)Binary transport listening on {}", bind_address);
+
+    tokio::spawn(serve_tcp(listener, move |request| {
+        let nodes = nodes.clone();
+        async move { answer_frame(&nodes, request).await }
+    }));
+    Ok(())
+}
+
+// Hand a request frame to the virtual node it is addressed to
+async fn answer_frame(nodes: &[web::Data<Node>], request: Request) -> Reply {
+    match request {
+        Request::Message { vnode, envelope } => match nodes.iter().find(|n| n.vnode == vnode) {
+            Some(node) => {
+                let (status, message) = receive_message(node, envelope).await;
+                Reply::Message {
+                    status: status.as_u16(),
+                    message,
+                }
+            }
+            None => Reply::Message {
+                status: 404,
+                message: Message::ErrorMessage {
+                    error: format!("No virtual node {}", vnode),
+                },
+            },
+        },
+        Request::Rpc { vnode, request } => {
+            Reply::Rpc(match nodes.iter().find(|n| n.vnode == vnode) {
+                Some(node) => match serve(node.get_ref(), request).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        log_message!(node, "Failed to serve rpc: {}", e);
+                        RpcResponse::Error(e)
+                    }
+                },
+                None => RpcResponse::Error(RpcError::Failed(format!("No virtual node {}", vnode))),
+            })
+        }
+    }
+}
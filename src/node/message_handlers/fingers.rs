@@ -1,50 +1,49 @@
 use super::*;
 
-pub async fn finger_req_handler(
-    ns: &NodeState,
-    from: String,
-    index: usize,
-    app_state: web::Data<Node>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let target_id = ns.id.clone();
-    let successor_id = match ns.successor.get_first() {
-        Some(id) => id.clone(),
-        None => return Ok(()),
-    };
-
-    if is_between(hash(&target_id), index as u32, hash(&successor_id)) {
-        let _ = send_post_request!(
-            &format!("http://{}/msg", from),
-            Message::ResFinger {
-                node_id: successor_id,
-                index
+// Resolve the successor of `id` one closest-preceding-finger hop at a time, asking each
+// node in turn. A finger that doesn't answer is dropped and the walk goes on through our
+// successor, which always makes progress.
+pub async fn resolve_successor(
+    app_state: &web::Data<Node>,
+    id: ChordId,
+) -> Result<String, RpcError> {
+    let ns = app_state.snapshot().await;
+    let mut step = find_successor(&ns, id);
+    for _ in 0..rpc::MAX_HOPS {
+        let next = match step {
+            Successor::Found(node_id) => return Ok(node_id),
+            Successor::Ask(next) => next,
+        };
+        step = match Peer::new(&next).find_successor(id).await {
+            Ok(step) => step,
+            Err(RpcError::Unreachable(e)) => {
+                let succ = {
+                    let mut ns = app_state.node_state.lock().await;
+                    ns.finger_table.remove_node(&next);
+                    ns.successor.get_first().cloned().unwrap_or(ns.id.clone())
+                };
+                if succ == next || succ == ns.id {
+                    return Err(RpcError::Unreachable(e));
+                }
+                log_message!(app_state, "Removed unreachable node {} from fingers", next);
+                Successor::Ask(succ)
             }
-        );
-    } else {
-        if let Err(e) = send_post_request!(
-            &format!("http://{}/msg", successor_id),
-            Message::ReqFinger { from, index }
-        ) {
-            log_message!(
-                &app_state,
-                "Failed to send finger request to successor: {}",
-                e
-            );
-        }
+            Err(e) => return Err(e),
+        };
     }
-    Ok(())
+    Err(RpcError::TooManyHops(id))
 }
 
-pub async fn finger_res_handler(
-    ns: &mut NodeState,
-    node_id: String,
-    index: usize,
-    app_state: web::Data<Node>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if ns
+// Point the finger starting at `index` to its current successor
+pub async fn fix_finger(app_state: &web::Data<Node>, index: ChordId) -> Result<(), RpcError> {
+    let node_id = resolve_successor(app_state, index).await?;
+    let updated = app_state
+        .node_state
+        .lock()
+        .await
         .finger_table
-        .update_entry(index.try_into().unwrap(), node_id.clone())
-    {
+        .update_entry(index, node_id.clone());
+    if updated {
         log_message!(
             app_state,
             "Updated finger table entry {} to {}",
@@ -52,17 +51,5 @@ pub async fn finger_res_handler(
             node_id
         );
     }
-
-    if let Some(next_index) = ns.finger_table.get_next_entry(index.try_into().unwrap()) {
-        if next_index != index as u32 {
-            send_post_request!(
-                &format!("http://{}/msg", ns.id.clone()),
-                Message::ReqFinger {
-                    from: ns.id.clone(),
-                    index: next_index as usize
-                }
-            )?;
-        }
-    }
     Ok(())
 }
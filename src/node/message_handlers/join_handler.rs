@@ -1,24 +1,55 @@
 use super::*;
 
 pub async fn req_join_handler(
-    ns: &mut NodeState,
     node_id: String,
     app_state_clone: web::Data<Node>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     log_message!(app_state_clone, "Join request from node {}", node_id);
+    app_state_clone.gossip.discover(&node_id).await;
 
-    let hash_node_id = hash(&ns.id);
-    let hash_successor_id = hash(&ns.successor.get_first().unwrap());
     let hash_joining_node = hash(&node_id);
 
+    // Decide where the joining node goes and claim it as our successor in one step,
+    // the messages to the others are sent once the lock is released
+    let (self_id, successor, collision, accepted) = {
+        let mut ns = app_state_clone.node_state.lock().await;
+        let mut successor = ns.successor.get_first().unwrap().clone();
+        let hash_node_id = hash(&ns.id);
+        let hash_successor_id = hash(&successor);
+
+        // Our successor restarting on the same address takes its old place back,
+        // with the successor it had before
+        let rejoin = successor == node_id;
+        if rejoin {
+            successor = ns
+                .successor
+                .entries
+                .iter()
+                .flatten()
+                .find(|succ| **succ != node_id)
+                .unwrap_or(&ns.id)
+                .clone();
+        }
+
+        let collision = hash_node_id == hash_joining_node
+            || (!rejoin && hash_successor_id == hash_joining_node);
+        // The joining node's hash falls between the current node and its successor
+        let accepted = !collision
+            && (rejoin || is_between(hash_node_id, hash_joining_node, hash_successor_id));
+        if accepted && !rejoin {
+            ns.successor.insert_first(node_id.clone());
+        }
+        (ns.id.clone(), successor, collision, accepted)
+    };
+
     // Check for hash collision
-    if hash_node_id == hash_joining_node || hash_successor_id == hash_joining_node {
+    if collision {
         log_message!(
             app_state_clone,
             "Node {} cannot join: hash collision detected",
             node_id
         );
-        match send_post_request!(&format!("http://{}/msg", node_id), Message::NodeExists) {
+        match send_message!(&node_id, Message::NodeExists) {
             Ok(_) => {}
             Err(e) => {
                 log_message!(
@@ -29,66 +60,65 @@ pub async fn req_join_handler(
                 );
             }
         }
-    } else {
-        // Determine the appropriate successor for the joining node
-        if is_between(hash_node_id, hash_joining_node, hash_successor_id) {
-            // The joining node's hash falls between the current node and its successor
-            let old_successor = ns.successor.get_first().unwrap().clone();
-            ns.successor.insert_first(node_id.clone());
-
-            // Notify the joining node of its successor
-            send_post_request!(
-                &format!("http://{}/msg", node_id),
-                Message::ResJoin {
-                    node_id: old_successor.clone(),
-                    sender_id: ns.id.clone()
-                }
-            )?;
-
-            // Notify the old successor for its new predecessor
-            send_post_request!(
-                &format!("http://{}/msg", old_successor.clone()),
-                Message::Notify {
-                    node_id: node_id.clone()
-                }
-            )?;
-        } else {
-            // Forward the join request to the successor
-            send_post_request!(
-                &format!("http://{}/msg", ns.successor.get_first().unwrap()),
-                Message::ReqJoin {
-                    node_id: node_id.clone()
-                }
-            )?;
+    } else if accepted {
+        // Notify the joining node of its successor
+        if let Err(e) = send_message!(
+            &node_id,
+            Message::ResJoin {
+                node_id: successor.clone(),
+                sender_id: self_id
+            }
+        ) {
+            // It never learns its place (gone, or turned down our protocol version),
+            // so hand the successor slot back
+            let mut ns = app_state_clone.node_state.lock().await;
+            if ns.successor.get_first() == Some(&node_id) {
+                ns.successor.insert_first(successor.clone());
+            }
+            return Err(Box::new(e));
         }
+
+        // Notify the old successor for its new predecessor
+        Peer::new(&successor).notify(&node_id).await?;
+    } else {
+        // Forward the join request to the successor
+        send_message!(
+            &successor,
+            Message::ReqJoin {
+                node_id: node_id.clone()
+            }
+        )?;
     }
     Ok(())
 }
 
 pub async fn res_join_handler(
-    ns: &mut NodeState,
     node_id: String,
     sender_id: String,
     app_state_clone: web::Data<Node>,
-    chord_ring: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Clear current successor list and add new successor
-    ns.successor.clear();
-    ns.successor.insert_first(node_id.clone());
+    // Try to get successor's successor list before taking the lock
+    let mut successors = vec![Some(node_id.clone())];
+    if let Ok(succ_list) = Peer::new(&node_id).get_successor_list().await {
+        successors.extend(succ_list.into_iter().take(*N - 1).map(Some));
+    }
 
-    // Try to get successor's successor list
-    if let Ok(response) = send_get_request!(&format!("http://{}/successors", node_id)) {
-        if let Ok(succ_list) = response.json::<Vec<String>>().await {
-            for (i, succ) in succ_list.into_iter().enumerate() {
-                if i + 1 < *N {
-                    // Skip first as we already have it
-                    ns.successor.insert(i + 1, Some(succ));
-                }
-            }
+    // Clear current successor list and add the new successors and predecessor
+    let (self_id, first_finger) = {
+        let mut ns = app_state_clone.node_state.lock().await;
+        ns.successor.clear();
+        for (i, succ) in successors.into_iter().enumerate() {
+            ns.successor.insert(i, succ);
         }
-    }
+        ns.predecessor = Some(sender_id.clone());
+        (ns.id.clone(), ns.finger_table.get_first_entry())
+    };
+    app_state_clone.joined.send_replace(true);
 
-    ns.predecessor = Some(sender_id.clone());
+    // Start from the successor's view of the membership, gossip keeps it current from here
+    app_state_clone.gossip.discover(&node_id).await;
+    app_state_clone.gossip.discover(&sender_id).await;
+    app_state_clone.gossip.sync_from(&node_id).await;
 
     log_message!(
         app_state_clone,
@@ -97,20 +127,36 @@ pub async fn res_join_handler(
         sender_id
     );
 
-    send_post_request!(
-        &format!("http://{}/msg", chord_ring),
-        Message::ResKnownNode {
-            node_id: ns.id.clone()
-        }
-    )?;
+    // Keys persisted from a previous run that fall outside (predecessor, self] are no longer ours,
+    // unless the store is shared with other virtual nodes that may still own them
+    let demoted = if *VNODES == 1 {
+        app_state_clone
+            .demote_data(hash(&self_id), hash(&sender_id))
+            .await?
+    } else {
+        0
+    };
+    if demoted > 0 {
+        log_message!(
+            app_state_clone,
+            "Moved {} persisted keys outside our range to replicas",
+            demoted
+        );
+    }
 
-    send_post_request!(
-        &format!("http://{}/msg", node_id),
-        Message::ReqFinger {
-            from: ns.id.clone(),
-            index: ns.finger_table.get_first_entry() as usize
-        }
-    )?;
+    notify_ring(Message::ResKnownNode {
+        node_id: self_id.clone(),
+    })
+    .await;
+
+    if let Err(e) = fix_finger(&app_state_clone, first_finger).await {
+        log_message!(
+            app_state_clone,
+            "Failed to fix finger {}: {}",
+            first_finger,
+            e
+        );
+    }
 
     Ok(())
 }
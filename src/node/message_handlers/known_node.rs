@@ -1,38 +1,39 @@
 use super::*;
 
 pub async fn known_node_handler(
-    ns: &mut NodeState,
     node_id: String,
     app_state_clone: web::Data<Node>,
-    chrod_ring: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if node_id != ns.id {
-        match send_post_request!(
-            &format!("http://{}/msg", node_id),
+    let self_id = app_state_clone.node_state.lock().await.id.clone();
+    if node_id != self_id {
+        match send_message!(
+            &node_id,
             Message::ReqJoin {
-                node_id: ns.id.clone()
+                node_id: self_id.clone()
             }
         ) {
             Ok(_) => Ok(()),
+            Err(TransportError::Rejected(426, error)) => {
+                log_message!(
+                    app_state_clone,
+                    "{} turned down the join: {}",
+                    node_id,
+                    error
+                );
+                exit_incompatible(&node_id, &error)
+            }
             Err(_) => {
                 log_message!(
                     app_state_clone,
                     "Failed to send join request to node {}",
                     node_id
                 );
-                send_post_request!(
-                    &format!("http://{}/msg", chrod_ring),
-                    Message::CheckNode {
-                        node_id: node_id.clone()
-                    }
-                )?;
+                notify_ring(Message::CheckNode {
+                    node_id: node_id.clone(),
+                })
+                .await;
                 tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                send_post_request!(
-                    &format!("http://{}/msg", chrod_ring),
-                    Message::ReqKnownNode {
-                        node_id: ns.id.clone()
-                    }
-                )?;
+                notify_ring(Message::ReqKnownNode { node_id: self_id }).await;
                 Ok(())
             }
         }
@@ -41,6 +42,7 @@ pub async fn known_node_handler(
             app_state_clone,
             "Node is currently the only node in the ring"
         );
+        app_state_clone.joined.send_replace(true);
         Ok(())
     }
 }
@@ -1,14 +1,21 @@
 use super::*;
 
 pub async fn leave_handler(
-    ns: &mut NodeState,
     node_id: String,
     app_state_clone: web::Data<Node>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     log_message!(app_state_clone, "Node {} left the ring", node_id);
-    if let Some(succ) = ns.successor.get_first() {
-        if *succ != node_id {
-            send_post_request!(&format!("http://{}/msg", succ), Message::Leave { node_id })?;
+    app_state_clone.gossip.dead(&node_id).await;
+    let successor = app_state_clone
+        .node_state
+        .lock()
+        .await
+        .successor
+        .get_first()
+        .cloned();
+    if let Some(succ) = successor {
+        if succ != node_id {
+            send_message!(&succ, Message::Leave { node_id })?;
         }
     }
     Ok(())
@@ -1,123 +1,156 @@
 use super::*;
 
 pub async fn lookup_req_handler(
-    ns: &NodeState,
     app_state: web::Data<Node>,
+    id: u64,
     key: String,
     hops: usize,
-    chord_ring: String,
+    reply_to: String,
+    mut path: Vec<String>,
+    query: Query,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let hash_key = hash(&key);
-    let hash_node_id = hash(&ns.id);
-    let hash_successor_id = hash(&ns.successor.get_first().unwrap());
-    let hash_predecessor_id = hash(&ns.predecessor.as_ref().unwrap());
+    let ns = app_state.snapshot().await;
+    path.push(ns.id.clone());
 
-    if is_between(hash_predecessor_id, hash_key, hash_node_id) {
-        // case 1: key belongs to the current node
-        let data = app_state.select_specific_data(key.clone()).await.unwrap();
-        send_post_request!(
-            &format!("http://{}/msg", chord_ring),
-            Message::LookupRes {
-                key,
-                hops,
-                data: Some(data)
-            }
-        )?;
-    } else if is_between(hash_node_id, hash_key, hash_successor_id) {
-        // case 2: key belongs to the successor
-        match send_post_request!(
-            &format!("http://{}/msg", ns.successor.get_first().unwrap()),
-            Message::LookupReq {
-                key: key.clone(),
-                hops: hops + 1
-            }
-        ) {
-            Ok(_) => (),
-            Err(e) => {
-                send_post_request!(
-                    &format!("http://{}/msg", chord_ring),
-                    Message::LookupRes {
-                        key: key.clone(),
-                        hops,
-                        data: None
-                    }
-                )?;
-                log_message!(app_state, "Failed to send lookup request: {}", e)
+    match route(&ns, hash(&key)) {
+        Route::Local => {
+            let mut data = app_state.select_specific_data(key.clone()).await?;
+            if data.is_empty() {
+                // the range may have just been taken over from a dead predecessor
+                data = app_state.select_replica_data(key.clone()).await?;
             }
+            let data = query.apply(data);
+            send_message!(
+                &reply_to,
+                Message::LookupRes {
+                    id,
+                    key,
+                    hops,
+                    node: Some(ns.id.clone()),
+                    data: Some(data),
+                    path
+                }
+            )?;
         }
-    } else {
-        // Iterate over finger table entries 2 by 2
-        let finger_entries = &ns.finger_table.entries;
-        let mut found = false;
-
-        for i in 0..finger_entries.len() - 1 {
-            let entry1 = &finger_entries[i];
-            let entry2 = &finger_entries[i + 1];
-
-            if is_between(entry1.start, hash_key, entry2.start) {
-                if let Some(ref node_id) = entry1.id {
-                    match send_post_request!(
-                        &format!("http://{}/msg", node_id),
-                        Message::LookupReq {
-                            key: key.clone(),
-                            hops: hops + 1
-                        }
-                    ) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            send_post_request!(
-                                &format!("http://{}/msg", chord_ring),
-                                Message::LookupRes {
-                                    key: key.clone(),
-                                    hops,
-                                    data: None
-                                }
-                            )?;
-                            log_message!(app_state, "Failed to send lookup request: {}", e)
-                        }
-                    }
-                    found = true;
-                    break;
+        Route::Forward(_) if hops + 1 >= rpc::MAX_HOPS => {
+            log_message!(
+                app_state,
+                "Dropping lookup for key '{}' after {} hops",
+                key,
+                hops + 1
+            );
+            send_message!(
+                &reply_to,
+                Message::LookupRes {
+                    id,
+                    key,
+                    hops,
+                    node: None,
+                    data: None,
+                    path
                 }
-            }
+            )?;
         }
+        Route::Forward(candidates) => {
+            let forwarded = forward_message(
+                &app_state,
+                &candidates,
+                Message::LookupReq {
+                    id,
+                    key: key.clone(),
+                    hops: hops + 1,
+                    reply_to: reply_to.clone(),
+                    path: path.clone(),
+                    query: query.clone(),
+                },
+            )
+            .await;
 
-        if !found {
-            // Loop through finger table in reverse until successful
-            for entry in finger_entries.iter().rev() {
-                if let Some(ref node_id) = entry.id {
-                    match send_post_request!(
-                        &format!("http://{}/msg", node_id),
-                        Message::LookupReq {
-                            key: key.clone(),
-                            hops: hops + 1
-                        }
-                    ) {
-                        Ok(_) => {
-                            found = true;
-                            break;
-                        }
-                        Err(_) => continue,
-                    }
-                }
+            if forwarded.is_some() {
+                return Ok(());
             }
 
-            // If still not found after trying all fingers, send lookup failure
-            if !found {
-                send_post_request!(
-                    &format!("http://{}/msg", chord_ring),
-                    Message::LookupRes {
-                        key: key.clone(),
-                        hops,
-                        data: None
-                    }
-                )?;
+            // The primary is unreachable, ask the successors holding its replicas
+            let replica_holders: Vec<String> = ns
+                .successor
+                .entries
+                .iter()
+                .flatten()
+                .filter(|succ| **succ != ns.id && !candidates.contains(succ))
+                .cloned()
+                .collect();
+            let forwarded = forward_message(
+                &app_state,
+                &replica_holders,
+                Message::ReplicaLookupReq {
+                    id,
+                    key: key.clone(),
+                    hops: hops + 1,
+                    reply_to: reply_to.clone(),
+                    path: path.clone(),
+                    query: query.clone(),
+                },
+            )
+            .await;
+
+            if forwarded.is_none() {
                 log_message!(
                     app_state,
                     "Failed to find node for key after trying all fingers"
                 );
+                send_message!(
+                    &reply_to,
+                    Message::LookupRes {
+                        id,
+                        key,
+                        hops,
+                        node: None,
+                        data: None,
+                        path
+                    }
+                )?;
             }
         }
     }
     Ok(())
 }
+
+// Answer a lookup from our primary copies and replicas, wherever the key falls
+pub async fn replica_lookup_handler(
+    app_state: web::Data<Node>,
+    id: u64,
+    key: String,
+    hops: usize,
+    reply_to: String,
+    mut path: Vec<String>,
+    query: Query,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let node_id = app_state.node_state.lock().await.id.clone();
+    path.push(node_id.clone());
+
+    let mut data = app_state.select_specific_data(key.clone()).await?;
+    if data.is_empty() {
+        data = app_state.select_replica_data(key.clone()).await?;
+    }
+
+    let data = query.apply(data);
+    log_message!(
+        app_state,
+        "Answering lookup for key '{}' from replicas ({} found)",
+        key,
+        data.len()
+    );
+
+    send_message!(
+        &reply_to,
+        Message::LookupRes {
+            id,
+            key,
+            hops,
+            node: Some(node_id),
+            data: Some(data),
+            path
+        }
+    )?;
+    Ok(())
+}
@@ -6,6 +6,8 @@ pub mod known_node;
 pub mod leave_handler;
 pub mod lookup;
 pub mod notify;
+pub mod routing;
+pub mod write;
 
 pub use fingers::*;
 pub use join_handler::*;
@@ -13,3 +15,5 @@ pub use known_node::*;
 pub use leave_handler::*;
 pub use lookup::*;
 pub use notify::*;
+pub use routing::*;
+pub use write::*;
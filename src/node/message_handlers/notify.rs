@@ -1,43 +1,72 @@
 use super::*;
 
+// `node_id` thinks it might be our predecessor, true if we took it as one
 pub async fn notify_handler(
-    ns: &mut NodeState,
     node_id: String,
-    app_state: web::Data<Node>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let hash_node_id = hash(&ns.id);
-    let hash_predecessor_id = ns.predecessor.as_ref().map_or(hash_node_id, |id| hash(id));
+    app_state: &Node,
+) -> Result<bool, Box<dyn std::error::Error>> {
     let hash_sender = hash(&node_id);
 
-    if !is_between(hash_predecessor_id, hash_sender, hash_node_id) {
-        return Ok(());
-    }
+    // Accept the new predecessor under the lock, the handover below runs without it
+    let (self_id, hash_predecessor_id, was_unknown) = {
+        let mut ns = app_state.node_state.lock().await;
+        let hash_node_id = hash(&ns.id);
+        let hash_predecessor_id = match &ns.predecessor {
+            Some(id) => {
+                if !is_between(hash(id), hash_sender, hash_node_id) {
+                    return Ok(false);
+                }
+                hash(id)
+            }
+            // our predecessor died, anyone may take its place
+            None => closest_known_predecessor(&ns).map_or(hash_node_id, |id| hash(&id)),
+        };
+        let was_unknown = ns.predecessor.is_none();
+        ns.predecessor = Some(node_id.clone());
+        (ns.id.clone(), hash_predecessor_id, was_unknown)
+    };
+    let hash_node_id = hash(&self_id);
 
-    ns.predecessor = Some(node_id.clone());
     log_message!(app_state, "Updated predecessor to {}", node_id);
 
+    if was_unknown {
+        // the range up to the new predecessor is ours now, serve it from our replicas
+        let promoted = app_state
+            .promote_replicas(hash_sender, hash_node_id)
+            .await?;
+        if !promoted.is_empty() {
+            log_message!(
+                app_state,
+                "Promoted {} replicas to primary copies",
+                promoted.len()
+            );
+        }
+    }
+
+    // A virtual node on our own host reads the same store, there is nothing to hand over
+    if host_of(&node_id) == host_of(&self_id) {
+        return Ok(true);
+    }
+
     let data_to_transfer = app_state
         .select_data(Some(hash_predecessor_id), Some(hash_sender))
         .await?;
 
     if data_to_transfer.is_empty() {
-        return Ok(());
+        return Ok(true);
     }
 
     log_message!(app_state, "Transfer data to node {}", node_id);
 
-    send_post_request!(
-        &format!("http://{}/msg", node_id),
-        Message::Data {
-            from: ns.id.clone(),
-            data: data_to_transfer
-        }
-    )?;
+    // the keys are only ours to drop once the new predecessor confirms it stored them
+    Peer::new(&node_id)
+        .transfer_keys(&self_id, data_to_transfer)
+        .await?;
 
     log_message!(app_state, "Remove data from node");
     app_state
         .remove_data(Some(hash_predecessor_id), Some(hash_sender))
         .await?;
 
-    Ok(())
+    Ok(true)
 }
@@ -0,0 +1,324 @@
+use super::*;
+
+pub enum Route {
+    // the key belongs to the current node
+    Local,
+    // nodes to forward to, in the order they should be tried
+    Forward(Vec<String>),
+}
+
+// The known node that most closely precedes us, standing in for a predecessor that died
+// until a new one notifies us
+pub fn closest_known_predecessor(ns: &NodeState) -> Option<String> {
+    let hash_node_id = hash(&ns.id);
+    ns.finger_table
+        .entries
+        .iter()
+        .filter_map(|entry| entry.id.as_ref())
+        .chain(ns.successor.entries.iter().flatten())
+        .filter(|id| **id != ns.id)
+        .max_by_key(|id| hash(id).wrapping_sub(hash_node_id))
+        .cloned()
+}
+
+pub fn route(ns: &NodeState, hash_key: ChordId) -> Route {
+    let hash_node_id = hash(&ns.id);
+    let hash_predecessor_id = match &ns.predecessor {
+        Some(id) => hash(id),
+        None => closest_known_predecessor(ns).map_or(hash_node_id, |id| hash(&id)),
+    };
+    let successor_id = ns.successor.get_first().unwrap_or(&ns.id);
+
+    if is_between(hash_predecessor_id, hash_key, hash_node_id) || *successor_id == ns.id {
+        return Route::Local;
+    }
+
+    if is_between(hash_node_id, hash_key, hash(successor_id)) {
+        // key belongs to the successor
+        return Route::Forward(vec![successor_id.clone()]);
+    }
+
+    // Closest preceding node first: every finger or successor strictly between us and the key,
+    // nearest to the key first, so no hop ever overshoots it
+    let mut candidates: Vec<String> = ns
+        .finger_table
+        .entries
+        .iter()
+        .filter_map(|entry| entry.id.as_ref())
+        .chain(ns.successor.entries.iter().flatten())
+        .filter(|id| {
+            let h = hash(id);
+            **id != ns.id && h != hash_key && is_between(hash_node_id, h, hash_key)
+        })
+        .cloned()
+        .collect();
+    candidates.sort_by_key(|id| std::cmp::Reverse(hash(id).wrapping_sub(hash_node_id)));
+    candidates.dedup();
+
+    if !candidates.contains(successor_id) {
+        candidates.push(successor_id.clone());
+    }
+
+    Route::Forward(candidates)
+}
+
+// One step of Chord's find_successor: answer if the id falls between us and our successor,
+// otherwise hand over to the closest preceding finger
+pub fn find_successor(ns: &NodeState, id: ChordId) -> Successor {
+    let hash_node_id = hash(&ns.id);
+    let successor_id = ns.successor.get_first().unwrap_or(&ns.id);
+
+    if *successor_id == ns.id || is_between(hash_node_id, id, hash(successor_id)) {
+        return Successor::Found(successor_id.clone());
+    }
+
+    match ns.finger_table.closest_preceding_finger(hash_node_id, id) {
+        Some(finger) if *finger != ns.id => Successor::Ask(finger.clone()),
+        _ => Successor::Ask(successor_id.clone()),
+    }
+}
+
+// Send the message to the first candidate that accepts it, dropping the ones that
+// don't answer from the finger table so later hops skip them
+pub async fn forward_message(
+    app_state: &web::Data<Node>,
+    candidates: &[String],
+    message: Message,
+) -> Option<String> {
+    for node_id in candidates {
+        match send_message!(&node_id, message) {
+            Ok(_) => return Some(node_id.clone()),
+            // alive but overloaded, keep it in the fingers and try the next one
+            Err(e @ TransportError::Rejected(..)) => {
+                log_message!(app_state, "{} did not take the request: {}", node_id, e);
+            }
+            Err(e) => {
+                log_message!(app_state, "Failed to forward request to {}: {}", node_id, e);
+                let removed = app_state
+                    .node_state
+                    .lock()
+                    .await
+                    .finger_table
+                    .remove_node(node_id);
+                if removed {
+                    log_message!(
+                        app_state,
+                        "Removed unreachable node {} from fingers",
+                        node_id
+                    );
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> ChordId {
+        n.to_string().parse().unwrap()
+    }
+
+    // a few nodes with distinct positions, ordered around the ring
+    fn ring() -> Vec<String> {
+        let mut nodes: Vec<String> = Vec::new();
+        for i in 0.. {
+            let node = format!("10.0.0.{}:3001", i);
+            if nodes.iter().all(|n| hash(n) != hash(&node)) {
+                nodes.push(node);
+            }
+            if nodes.len() == 5 {
+                break;
+            }
+        }
+        nodes.sort_by_key(|n| hash(n));
+        nodes
+    }
+
+    // the node responsible for `key`: the first one at or after it
+    fn owner(nodes: &[String], key: ChordId) -> String {
+        nodes
+            .iter()
+            .find(|n| hash(n) >= key)
+            .unwrap_or(&nodes[0])
+            .clone()
+    }
+
+    fn state(nodes: &[String], at: usize, fingers: bool) -> NodeState {
+        let mut ns = NodeState::new(nodes[at].clone());
+        ns.predecessor = Some(nodes[(at + nodes.len() - 1) % nodes.len()].clone());
+        for i in 0..*N {
+            ns.successor
+                .insert(i, Some(nodes[(at + 1 + i) % nodes.len()].clone()));
+        }
+        // fingers on the following nodes in ring order, so every node knows all the others
+        if fingers {
+            for (i, entry) in ns.finger_table.entries.iter_mut().enumerate() {
+                entry.id = Some(nodes[(at + 1 + i) % nodes.len()].clone());
+            }
+        }
+        ns
+    }
+
+    // keys on, just before and just after every node
+    fn keys(nodes: &[String]) -> Vec<ChordId> {
+        nodes
+            .iter()
+            .flat_map(|n| {
+                let h = hash(n);
+                [h.wrapping_sub(id(1)), h, h.wrapping_add(id(1))]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn route_table() {
+        let nodes = ring();
+        let first = hash(&nodes[0]);
+        let last = nodes.len() - 1;
+        // (from, key, Some(first candidate) or None for local)
+        let cases = [
+            // a key equal to a node id belongs to that node
+            (0, first, None),
+            (1, hash(&nodes[1]), None),
+            (0, hash(&nodes[1]), Some(&nodes[1])),
+            // past the last node the key wraps around to the first one
+            (
+                last,
+                hash(&nodes[last]).wrapping_add(id(1)),
+                Some(&nodes[0]),
+            ),
+            (0, hash(&nodes[last]).wrapping_add(id(1)), None),
+            (last, first, Some(&nodes[0])),
+            // a key just before our own id is ours
+            (2, hash(&nodes[2]).wrapping_sub(id(1)), None),
+        ];
+        for (at, key, expected) in cases {
+            let ns = state(&nodes, at, true);
+            match (route(&ns, key), expected) {
+                (Route::Local, None) => {}
+                (Route::Forward(candidates), Some(node)) => assert_eq!(&candidates[0], node),
+                (Route::Local, Some(node)) => {
+                    panic!("{} kept {}, expected {}", nodes[at], key, node)
+                }
+                (Route::Forward(c), None) => panic!("{} forwarded {} to {:?}", nodes[at], key, c),
+            }
+        }
+    }
+
+    #[test]
+    fn route_reaches_the_owner_without_overshooting() {
+        let nodes = ring();
+        for fingers in [true, false] {
+            for key in keys(&nodes) {
+                for start in 0..nodes.len() {
+                    let mut at = start;
+                    let mut hops = 0;
+                    while let Route::Forward(candidates) = route(&state(&nodes, at, fingers), key) {
+                        let next = &candidates[0];
+                        // every hop stays between the current node and the key's owner
+                        let here = hash(&nodes[at]);
+                        assert!(is_between(here, hash(next), hash(&owner(&nodes, key))));
+                        at = nodes.iter().position(|n| n == next).unwrap();
+                        hops += 1;
+                        assert!(hops <= nodes.len(), "{} loops from {}", key, nodes[start]);
+                    }
+                    assert_eq!(nodes[at], owner(&nodes, key));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn route_without_fingers_uses_the_successor() {
+        let nodes = ring();
+        let mut ns = state(&nodes, 0, false);
+        ns.successor.clear();
+        ns.successor.insert_first(nodes[1].clone());
+        match route(&ns, hash(&nodes[3])) {
+            Route::Forward(candidates) => assert_eq!(candidates, vec![nodes[1].clone()]),
+            Route::Local => panic!("key kept locally"),
+        }
+
+        // alone on the ring, every key is ours
+        let alone = NodeState::new(nodes[0].clone());
+        for key in keys(&nodes) {
+            assert!(matches!(route(&alone, key), Route::Local));
+        }
+    }
+
+    #[test]
+    fn find_successor_table() {
+        let nodes = ring();
+        let last = nodes.len() - 1;
+        let cases = [
+            // between us and our successor, including its own id
+            (0, hash(&nodes[1]), Successor::Found(nodes[1].clone())),
+            (
+                0,
+                hash(&nodes[0]).wrapping_add(id(1)),
+                Successor::Found(nodes[1].clone()),
+            ),
+            // wrapping past the last node
+            (last, hash(&nodes[0]), Successor::Found(nodes[0].clone())),
+            (
+                last,
+                hash(&nodes[last]).wrapping_add(id(1)),
+                Successor::Found(nodes[0].clone()),
+            ),
+            // further away, ask the closest preceding finger
+            (0, hash(&nodes[3]), Successor::Ask(nodes[2].clone())),
+            (1, hash(&nodes[0]), Successor::Ask(nodes[last].clone())),
+        ];
+        for (at, key, expected) in cases {
+            assert_eq!(find_successor(&state(&nodes, at, true), key), expected);
+        }
+
+        // no fingers yet, the successor is the only one to ask
+        let ns = state(&nodes, 0, false);
+        assert_eq!(
+            find_successor(&ns, hash(&nodes[3])),
+            Successor::Ask(nodes[1].clone())
+        );
+        // a node alone on the ring is the successor of everything
+        let alone = NodeState::new(nodes[0].clone());
+        assert_eq!(
+            find_successor(&alone, hash(&nodes[3])),
+            Successor::Found(nodes[0].clone())
+        );
+    }
+
+    #[test]
+    fn closest_preceding_finger_table() {
+        let nodes = ring();
+        let ns = state(&nodes, 0, true);
+        let here = hash(&nodes[0]);
+        let fingers = &ns.finger_table;
+        // the finger nearest below the key, never one sitting on it
+        assert_eq!(
+            fingers.closest_preceding_finger(here, hash(&nodes[3])),
+            Some(&nodes[2])
+        );
+        assert_eq!(
+            fingers.closest_preceding_finger(here, hash(&nodes[3]).wrapping_add(id(1))),
+            Some(&nodes[3])
+        );
+        // nothing lies strictly between us and our successor
+        assert_eq!(
+            fingers.closest_preceding_finger(here, hash(&nodes[1])),
+            None
+        );
+        // wrapping around, every other node precedes our own id
+        assert_eq!(
+            fingers.closest_preceding_finger(here, here),
+            Some(&nodes[nodes.len() - 1])
+        );
+
+        let empty = state(&nodes, 0, false);
+        for key in keys(&nodes) {
+            assert_eq!(empty.finger_table.closest_preceding_finger(here, key), None);
+        }
+    }
+}
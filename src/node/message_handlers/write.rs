@@ -0,0 +1,157 @@
+use super::*;
+
+pub async fn put_handler(
+    app_state: web::Data<Node>,
+    id: u64,
+    data: Data,
+    mode: WriteMode,
+    hops: usize,
+    reply_to: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ns = app_state.snapshot().await;
+    match route(&ns, hash(&data.key)) {
+        Route::Local => {
+            log_message!(app_state, "Storing key '{}' ({:?})", data.key, mode);
+            let key = data.key.clone();
+            let (skipped, error) = match app_state.insert_batch_data(vec![data], mode).await {
+                Ok(written) => (written == 0, None),
+                // a conflict is the requester's to hear about, anything else is ours
+                Err(e @ StoreError::Conflict(_)) => (false, Some(e.to_string())),
+                Err(e) => return Err(Box::new(e)),
+            };
+            send_message!(
+                &reply_to,
+                Message::PutAck {
+                    id,
+                    key,
+                    hops,
+                    node: Some(ns.id.clone()),
+                    skipped,
+                    error
+                }
+            )?;
+        }
+        Route::Forward(_) if hops + 1 >= rpc::MAX_HOPS => {
+            log_message!(
+                app_state,
+                "Dropping put for key '{}' after {} hops",
+                data.key,
+                hops + 1
+            );
+            send_message!(
+                &reply_to,
+                Message::PutAck {
+                    id,
+                    key: data.key,
+                    hops,
+                    node: None,
+                    skipped: false,
+                    error: Some(format!("not delivered after {} hops", hops + 1))
+                }
+            )?;
+        }
+        Route::Forward(candidates) => {
+            let key = data.key.clone();
+            let forwarded = forward_message(
+                &app_state,
+                &candidates,
+                Message::Put {
+                    id,
+                    data,
+                    mode,
+                    hops: hops + 1,
+                    reply_to: reply_to.clone(),
+                },
+            )
+            .await;
+
+            if forwarded.is_none() {
+                log_message!(app_state, "Failed to route put for key '{}'", key);
+                send_message!(
+                    &reply_to,
+                    Message::PutAck {
+                        id,
+                        key,
+                        hops,
+                        node: None,
+                        skipped: false,
+                        error: None
+                    }
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn delete_handler(
+    app_state: web::Data<Node>,
+    id: u64,
+    key: String,
+    hops: usize,
+    reply_to: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ns = app_state.snapshot().await;
+    match route(&ns, hash(&key)) {
+        Route::Local => {
+            let removed = app_state.delete_specific_data(key.clone()).await?;
+            log_message!(app_state, "Deleted key '{}' ({} rows)", key, removed);
+            send_message!(
+                &reply_to,
+                Message::DeleteAck {
+                    id,
+                    key,
+                    hops,
+                    node: Some(ns.id.clone()),
+                    removed
+                }
+            )?;
+        }
+        Route::Forward(_) if hops + 1 >= rpc::MAX_HOPS => {
+            log_message!(
+                app_state,
+                "Dropping delete for key '{}' after {} hops",
+                key,
+                hops + 1
+            );
+            send_message!(
+                &reply_to,
+                Message::DeleteAck {
+                    id,
+                    key,
+                    hops,
+                    node: None,
+                    removed: 0
+                }
+            )?;
+        }
+        Route::Forward(candidates) => {
+            let forwarded = forward_message(
+                &app_state,
+                &candidates,
+                Message::Delete {
+                    id,
+                    key: key.clone(),
+                    hops: hops + 1,
+                    reply_to: reply_to.clone(),
+                },
+            )
+            .await;
+
+            if forwarded.is_none() {
+                log_message!(app_state, "Failed to route delete for key '{}'", key);
+                send_message!(
+                    &reply_to,
+                    Message::DeleteAck {
+                        id,
+                        key,
+                        hops,
+                        node: None,
+                        removed: 0
+                    }
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,17 +1,27 @@
 use super::*;
+use clock::*;
 use data::*;
+use gossip::gossip::*;
 use handlers::*;
-use hash::hash;
+use hash::{hash, ChordId};
 use helper::*;
 use message_handlers::*;
+use metrics::*;
 use msg::*;
 use node_state::*;
+use pending::*;
+use query::*;
+use replies::*;
+use rpc::rpc::*;
+use store::*;
+use transport::*;
 
 pub mod finger_table;
 pub mod handlers;
 pub mod helper;
 pub mod message_handlers;
 pub mod node;
+pub mod store;
 pub mod succ_table;
 
 const HTML_PATH: &str = "./src/node/client/template.html";
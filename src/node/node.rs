@@ -1,191 +1,174 @@
 use super::*;
+use std::collections::HashMap;
+use tokio::sync::{watch, Semaphore};
 use tokio::time::interval;
 
 lazy_static::lazy_static! {
-    pub static ref CHORD_RING: Mutex<String> = Mutex::new("".to_string());
+    // the ChordRing dashboard this node reports to, if any
+    pub static ref CHORD_RING: Mutex<Option<String>> = Mutex::new(None);
+    // ring members to join through
+    pub static ref SEED_NODES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// Tell the ChordRing about a membership change. It only observes the ring, so it
+// being unset or down is not an error.
+pub async fn notify_ring(message: Message) {
+    let ring = CHORD_RING.lock().await.clone();
+    if let Some(chord_ring) = ring {
+        let _ = send_message!(&chord_ring, message, 1);
+    }
+}
+
+// A peer that turns down our protocol version won't take us in on a retry either
+pub fn exit_incompatible(peer: &str, error: &str) -> ! {
+    println!("Cannot join the ring through {}: {}", peer, error);
+    std::process::exit(1);
+}
+
+// Messages that only read the node state, handled outside the serial message loop
+fn is_read_only(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::LookupReq { .. } | Message::ReplicaLookupReq { .. }
+    )
+}
+
+async fn handle_read_only(
+    app_state: web::Data<Node>,
+    message: Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match message {
+        Message::LookupReq {
+            id,
+            key,
+            hops,
+            reply_to,
+            path,
+            query,
+        } => lookup_req_handler(app_state, id, key, hops, reply_to, path, query).await,
+        Message::ReplicaLookupReq {
+            id,
+            key,
+            hops,
+            reply_to,
+            path,
+            query,
+        } => replica_lookup_handler(app_state, id, key, hops, reply_to, path, query).await,
+        _ => Ok(()),
+    }
 }
 
 #[derive(Debug)]
 pub struct Node {
-    pub db: Mutex<Connection>,
+    pub vnode: usize,
+    pub db: SharedStore,
+    pub replicas: SharedStore,
     pub node_state: Arc<Mutex<NodeState>>,
     pub tx: mpsc::Sender<Message>,
     pub logs: Arc<Mutex<Vec<String>>>,
+    pub pending: PendingRequests,
+    // membership view of the whole process, shared by its virtual nodes
+    pub gossip: Membership,
+    // bounds the read-only messages handled concurrently
+    pub workers: Arc<Semaphore>,
+    pub metrics: Metrics,
+    // set once the node has its place in a ring, one it joined or started
+    pub joined: watch::Sender<bool>,
 }
 
 impl Node {
-    pub fn new(port: Option<u16>) -> web::Data<Node> {
-        let conn = in_mem_db();
-        let node_id = match port {
+    // The VNODES virtual nodes hosted by this process, all sharing its stores
+    pub fn new_host(port: Option<u16>) -> Vec<web::Data<Node>> {
+        let host = match port {
             Some(p) => format!("{}:{}", *IP, p),
             None => format!("{}:3000", *IP),
         };
+        let (db, replicas) = open_stores(&host);
+        let db: SharedStore = Arc::new(Mutex::new(db));
+        let replicas: SharedStore = Arc::new(Mutex::new(replicas));
+        let gossip = Membership::new(Arc::new(Mutex::new(Vec::new())));
+        gossip.start();
+
+        // With a small M two virtual ids of the same host can land on one position,
+        // only the first of them is kept
+        let mut taken = Vec::new();
+        (0..*VNODES)
+            .filter(|vnode| {
+                let position = hash(&vnode_id(&host, *vnode));
+                if taken.contains(&position) {
+                    println!(
+                        "Skipping virtual node {}: hash collision on this host",
+                        vnode
+                    );
+                    return false;
+                }
+                taken.push(position);
+                true
+            })
+            .map(|vnode| Node::new(&host, vnode, db.clone(), replicas.clone(), gossip.clone()))
+            .collect()
+    }
+
+    pub fn new(
+        host: &str,
+        vnode: usize,
+        db: SharedStore,
+        replicas: SharedStore,
+        gossip: Membership,
+    ) -> web::Data<Node> {
+        let node_id = vnode_id(host, vnode);
         let node_state = Arc::new(Mutex::new(NodeState::new(node_id.clone())));
 
         let (tx, mut rx) = mpsc::channel(*DEFAULT_CHANNEL_SIZE);
 
         let app_state = web::Data::new(Node {
-            db: Mutex::new(conn),
+            vnode,
+            db,
+            replicas,
             node_state: node_state.clone(),
             tx: tx.clone(),
-            logs: Arc::new(Mutex::new(Vec::new())),
+            // gossip events show up on virtual node 0's dashboard
+            logs: if vnode == 0 {
+                gossip.logs.clone()
+            } else {
+                Arc::new(Mutex::new(Vec::new()))
+            },
+            pending: PendingRequests::new(),
+            gossip,
+            workers: Arc::new(Semaphore::new(*MAX_CONCURRENT_MESSAGES)),
+            metrics: Metrics::default(),
+            joined: watch::channel(false).0,
         });
 
-        // Spawn a task to handle messages from the channel
-        let node_state_clone = node_state.clone();
-        let tx_clone = tx.clone();
-
-        // update fingers periodically
+        // fix_fingers: refresh one finger table entry per tick, round-robin
+        let app_state_clone = app_state.clone();
         tokio::spawn(async move {
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-            let mut interval = interval(std::time::Duration::from_secs(5));
+            let mut interval = interval(std::time::Duration::from_secs(1));
             loop {
                 interval.tick().await;
-                let ns = node_state_clone.lock().await.clone();
-                if let Some(succ) = ns.successor.get_first() {
-                    if *succ != ns.id {
-                        let _ = tx_clone
-                            .send(Message::ReqFinger {
-                                from: ns.id.clone(),
-                                index: ns.finger_table.get_first_entry() as usize,
-                            })
-                            .await;
+                let index = {
+                    let mut ns = app_state_clone.node_state.lock().await;
+                    match ns.successor.get_first() {
+                        Some(succ) if *succ != ns.id => ns.finger_table.next_to_fix(),
+                        _ => continue,
                     }
+                };
+                if let Err(e) = fix_finger(&app_state_clone, index).await {
+                    log_message!(app_state_clone, "Failed to fix finger {}: {}", index, e);
                 }
             }
         });
 
         // stabilize the ring periodically
-        let node_state_clone = node_state.clone();
         let app_state_clone = app_state.clone();
         tokio::spawn(async move {
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
             let mut interval = interval(std::time::Duration::from_secs(5));
             loop {
                 interval.tick().await;
-                let mut ns = node_state_clone.lock().await;
-
-                // Skip if we're alone in the ring
-                if ns.successor.get_first() == Some(&ns.id) {
-                    continue;
-                }
-
-                // Check current successor list and remove dead nodes
-                let mut i = 0;
-                while i < *N {
-                    if let Some(succ) = ns.successor.entries[i].clone() {
-                        // Try to ping the successor
-                        let is_alive = match send_post_request!(
-                            &format!("http://{}/msg", succ),
-                            Message::Ping
-                        ) {
-                            Ok(response) => response.status().is_success(),
-                            Err(_) => false,
-                        };
-
-                        if !is_alive {
-                            log_message!(
-                                app_state_clone,
-                                "Successor {} is dead, removing from successor list",
-                                succ
-                            );
-
-                            if let Some(next_succ) =
-                                ns.successor.entries.get(i + 1).cloned().flatten()
-                            {
-                                // Notify the next successor that we are its new predecessor
-                                if let Some(prev_succ) = ns.successor.entries.get(i - 1).cloned() {
-                                    let _ = send_post_request!(
-                                        &format!("http://{}/msg", next_succ),
-                                        Message::IAmYourPredecessor {
-                                            node_id: prev_succ.unwrap()
-                                        }
-                                    );
-                                } else {
-                                    let _ = send_post_request!(
-                                        &format!("http://{}/msg", next_succ),
-                                        Message::IAmYourPredecessor {
-                                            node_id: ns.id.clone()
-                                        }
-                                    );
-                                }
-
-                                // Also notify the chord ring about the dead node
-                                let _ = send_post_request!(
-                                    &format!("http://{}/msg", CHORD_RING.lock().await.clone()),
-                                    Message::Leave {
-                                        node_id: succ.clone()
-                                    }
-                                );
-                            }
-
-                            // Remove dead successor
-                            ns.successor.remove_successor(&succ);
-                            // Don't increment i as we need to check the shifted successor
-                            continue;
-                        }
-                    }
-                    i += 1;
-                }
-
-                // If we lost our immediate successor, promote the next alive successor
-                if ns.successor.get_first().is_none() {
-                    for i in 1..*N {
-                        if let Some(succ) = ns.successor.entries[i].clone() {
-                            log_message!(
-                                app_state_clone,
-                                "Promoting {} to immediate successor",
-                                succ
-                            );
-                            ns.successor.clear();
-                            ns.successor.insert_first(succ);
-                            break;
-                        }
-                    }
-                }
-
-                // Update successor list with successors' successors
-                if let Some(immediate_succ) = ns.successor.get_first().cloned() {
-                    let res = send_get_request!(&format!("http://{}/successors", immediate_succ));
-                    if let Ok(response) = res {
-                        if let Ok(succ_list) = response.json::<Vec<String>>().await {
-                            let mut new_successors = vec![Some(immediate_succ)];
-
-                            // Add successors' successors to our list
-                            for succ in succ_list.into_iter().take(*N - 1) {
-                                // Verify each successor is alive before adding
-                                if send_post_request!(
-                                    &format!("http://{}/msg", succ),
-                                    Message::Ping
-                                )
-                                .is_ok()
-                                {
-                                    new_successors.push(Some(succ));
-                                }
-                            }
-
-                            // Pad with None if we don't have enough successors
-                            while new_successors.len() < *N {
-                                new_successors.push(None);
-                            }
-
-                            // Update successor list
-                            for (i, succ) in new_successors.into_iter().enumerate() {
-                                ns.successor.insert(i, succ);
-                            }
-                        }
-                    }
-                }
-
-                // If we still have a valid immediate successor, notify it
-                if let Some(succ) = ns.successor.get_first() {
-                    let _ = send_post_request!(
-                        &format!("http://{}/msg", succ),
-                        Message::Notify {
-                            node_id: ns.id.clone()
-                        }
-                    );
-                }
+                app_state_clone.check_predecessor().await;
+                app_state_clone.stabilize().await;
             }
         });
 
@@ -193,89 +176,115 @@ impl Node {
         let app_state_clone = app_state.clone();
         tokio::spawn(async move {
             while let Some(message) = rx.recv().await {
-                match message {
-                    Message::ResKnownNode { node_id } => {
-                        let mut ns = node_state_clone.lock().await;
-                        known_node_handler(
-                            &mut ns,
-                            node_id,
-                            app_state_clone.clone(),
-                            CHORD_RING.lock().await.clone(),
-                        )
+                app_state_clone.metrics.received();
+
+                // Read-only messages only route on a snapshot of the node state, so each gets
+                // a task of its own and a slow peer holds up nothing but its own request.
+                // Once MAX_CONCURRENT_MESSAGES of them run the loop waits for a free slot,
+                // the channel fills up and /msg starts turning senders away.
+                if is_read_only(&message) {
+                    let permit = app_state_clone
+                        .workers
+                        .clone()
+                        .acquire_owned()
                         .await
                         .unwrap();
+                    app_state_clone.metrics.concurrent();
+                    let app_state = app_state_clone.clone();
+                    tokio::spawn(async move {
+                        let result = handle_read_only(app_state.clone(), message).await;
+                        drop(permit);
+                        if let Err(e) = result.map_err(|e| e.to_string()) {
+                            app_state.metrics.failed();
+                            log_message!(app_state, "Failed to handle message: {}", e);
+                        }
+                    });
+                    continue;
+                }
+
+                let result = match message {
+                    Message::ResKnownNode { node_id } => {
+                        known_node_handler(node_id, app_state_clone.clone()).await
                     }
                     Message::Leave { node_id } => {
-                        let mut ns = node_state_clone.lock().await;
-                        leave_handler(&mut ns, node_id, app_state_clone.clone())
-                            .await
-                            .unwrap();
+                        leave_handler(node_id, app_state_clone.clone()).await
                     }
                     Message::ReqJoin { node_id } => {
-                        let mut ns = node_state_clone.lock().await;
-                        req_join_handler(&mut ns, node_id, app_state_clone.clone())
-                            .await
-                            .unwrap();
+                        req_join_handler(node_id, app_state_clone.clone()).await
                     }
                     Message::ResJoin { node_id, sender_id } => {
-                        let mut ns = node_state_clone.lock().await;
-                        res_join_handler(
-                            &mut ns,
-                            node_id,
-                            sender_id,
-                            app_state_clone.clone(),
-                            CHORD_RING.lock().await.clone(),
-                        )
-                        .await
-                        .unwrap();
-                    }
-                    Message::LookupReq { key, hops } => {
-                        let ns = node_state_clone.lock().await;
-                        lookup_req_handler(
-                            &ns,
-                            app_state_clone.clone(),
-                            key,
-                            hops,
-                            CHORD_RING.lock().await.clone(),
-                        )
-                        .await
-                        .unwrap();
-                    }
-                    Message::ReqFinger { from, index } => {
-                        let ns = node_state_clone.lock().await;
-                        finger_req_handler(&ns, from, index, app_state_clone.clone())
-                            .await
-                            .unwrap();
-                    }
-                    Message::ResFinger { node_id, index } => {
-                        let mut ns = node_state_clone.lock().await;
-                        finger_res_handler(&mut ns, node_id, index, app_state_clone.clone())
-                            .await
-                            .unwrap();
-                    }
-                    Message::Notify { node_id } => {
-                        let mut ns = node_state_clone.lock().await;
-                        notify_handler(&mut ns, node_id, app_state_clone.clone())
-                            .await
-                            .unwrap();
+                        res_join_handler(node_id, sender_id, app_state_clone.clone()).await
                     }
                     Message::IAmYourPredecessor { node_id } => {
                         log_message!(app_state_clone, "Update predecessor to node {}", node_id);
-                        let mut ns = node_state_clone.lock().await;
-                        ns.predecessor = Some(node_id.clone());
+                        let node_hash = {
+                            let mut ns = node_state_clone.lock().await;
+                            ns.predecessor = Some(node_id.clone());
+                            hash(&ns.id)
+                        };
+
+                        // Serve whatever we hold replicas of in the range we just took over
+                        match app_state_clone
+                            .promote_replicas(hash(&node_id), node_hash)
+                            .await
+                        {
+                            Ok(promoted) if !promoted.is_empty() => {
+                                log_message!(
+                                    app_state_clone,
+                                    "Promoted {} replicas to primary copies",
+                                    promoted.len()
+                                );
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                log_message!(app_state_clone, "Failed to promote replicas: {}", e);
+                            }
+                        }
+                        Ok(())
                     }
                     Message::IAmYourSuccessor { node_id } => {
                         log_message!(app_state_clone, "Update successor to node {}", node_id);
                         let mut ns = node_state_clone.lock().await;
                         ns.successor.clear();
                         ns.successor.insert_first(node_id.clone());
+                        Ok(())
+                    }
+                    Message::Put {
+                        id,
+                        data,
+                        mode,
+                        hops,
+                        reply_to,
+                    } => put_handler(app_state_clone.clone(), id, data, mode, hops, reply_to).await,
+                    Message::Delete {
+                        id,
+                        key,
+                        hops,
+                        reply_to,
+                    } => delete_handler(app_state_clone.clone(), id, key, hops, reply_to).await,
+                    Message::LookupRes { id, .. }
+                    | Message::PutAck { id, .. }
+                    | Message::DeleteAck { id, .. } => {
+                        app_state_clone.pending.resolve(id, message).await;
+                        Ok(())
                     }
-                    Message::Data { from, data } => {
-                        log_message!(app_state_clone, "Transfer data to node {}", from);
-                        let _ = app_state_clone.insert_batch_data(data).await;
+                    Message::Replicate {
+                        from,
+                        data,
+                        removed,
+                    } => {
+                        // Our own host already holds the primary copies in the shared store
+                        if host_of(&from) != host_of(&node_state_clone.lock().await.id) {
+                            let _ = app_state_clone.store_replicas(data, removed).await;
+                        }
+                        Ok(())
                     }
                     Message::NodeExists => {
                         log_message!(app_state_clone, "Node already exists in the ring");
+                        // A colliding virtual node just stays out, the rest of the host keeps running
+                        if app_state_clone.vnode != 0 {
+                            continue;
+                        }
                         // println!("Node hash collision detected - exiting");
                         std::process::exit(1);
                     }
@@ -286,7 +295,14 @@ impl Node {
                     }
                     _ => {
                         log_message!(app_state_clone, "Something unexpected was sent");
+                        Ok(())
                     }
+                };
+
+                // A node that died in the middle of a request must not take the loop down with it
+                if let Err(e) = result.map_err(|e| e.to_string()) {
+                    app_state_clone.metrics.failed();
+                    log_message!(app_state_clone, "Failed to handle message: {}", e);
                 }
             }
         });
@@ -294,77 +310,157 @@ impl Node {
         app_state
     }
 
+    // Join through the first seed that answers, falling back to asking the ChordRing for a
+    // known node. With neither seeds nor a ChordRing the node starts a new ring of its own.
+    // The other virtual nodes of the process join through virtual node 0 instead, so they
+    // end up on its ring rather than each starting one.
+    pub async fn join(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let node_id = self.node_state.lock().await.id.clone();
+        if self.vnode != 0 {
+            return self.join_through(&vnode_id(host_of(&node_id), 0)).await;
+        }
+        self.gossip.add_local(&node_id).await;
+        let seeds: Vec<String> = SEED_NODES
+            .lock()
+            .await
+            .iter()
+            .filter(|seed| host_of(seed) != host_of(&node_id))
+            .cloned()
+            .collect();
+
+        for seed in seeds.iter() {
+            log_message!(self, "Joining the ring through seed {}", seed);
+            match send_message!(
+                &seed,
+                Message::ReqJoin {
+                    node_id: node_id.clone()
+                },
+                1
+            ) {
+                Ok(_) => return Ok(()),
+                Err(TransportError::Rejected(426, error)) => {
+                    log_message!(self, "Seed {} turned down the join: {}", seed, error);
+                    exit_incompatible(seed, &error)
+                }
+                Err(_) => log_message!(self, "Seed {} is unreachable", seed),
+            }
+        }
+
+        // clone it out first so the lock isn't held while we talk to the ChordRing
+        let ring = CHORD_RING.lock().await.clone();
+        match ring {
+            Some(chord_ring) => self.req_known_node(chord_ring).await,
+            None if seeds.is_empty() => {
+                log_message!(self, "No seeds to join through, starting a new ring");
+                self.joined.send_replace(true);
+                Ok(())
+            }
+            None => Err(Box::new(std::io::Error::other("No seed node is reachable"))),
+        }
+    }
+
+    // Ask a node that is already in the ring to place us
+    pub async fn join_through(&self, node: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let node_id = self.node_state.lock().await.id.clone();
+        self.gossip.add_local(&node_id).await;
+        log_message!(self, "Joining the ring through {}", node);
+        match send_message!(node, Message::ReqJoin { node_id }, 1) {
+            Ok(_) => Ok(()),
+            Err(TransportError::Rejected(426, error)) => exit_incompatible(node, &error),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // Wait until the node has its place in a ring, after join only sent the request
+    pub async fn wait_joined(&self, timeout: std::time::Duration) -> bool {
+        let mut joined = self.joined.subscribe();
+        let waited = tokio::time::timeout(timeout, joined.wait_for(|joined| *joined)).await;
+        matches!(waited, Ok(Ok(_)))
+    }
+
     pub async fn req_known_node(&self, node: String) -> Result<(), Box<dyn std::error::Error>> {
         log_message!(self, "Requesting known node from node: {}", node);
-        *CHORD_RING.lock().await = node.clone();
-        let res = send_post_request!(
-            &format!("http://{}/msg", node),
+        match send_message!(
+            &node,
             Message::ReqKnownNode {
                 node_id: self.node_state.lock().await.id.clone()
             }
-        )?;
-
-        if res.status().is_success() {
-            Ok(())
-        } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Failed to send request",
-            )))
+        ) {
+            Ok(_) => Ok(()),
+            Err(TransportError::Rejected(426, error)) => {
+                log_message!(self, "The ChordRing turned down the join: {}", error);
+                exit_incompatible(&node, &error)
+            }
+            Err(e) => Err(Box::new(e)),
         }
     }
 
     pub async fn leave(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut node_state = self.node_state.lock().await;
+        let node_state = self.snapshot().await;
         let node_id = node_state.id.clone();
         let successor = node_state.successor.get_first().unwrap().clone();
-        let predecessor = node_state.predecessor.clone().unwrap();
+        let predecessor = node_state.predecessor.clone();
+        // our range starts at the predecessor, or at the closest node we know of if it died
+        let start = match &predecessor {
+            Some(pred) => hash(pred),
+            None => closest_known_predecessor(&node_state).map_or(hash(&node_id), |id| hash(&id)),
+        };
 
         // Only proceed if the node is not the only node in the ring
-        if successor != node_id.clone() && predecessor != node_id.clone() {
-            // 1. Transfer data to the successor
-            let _ = send_post_request!(
-                &format!("http://{}/msg", successor),
-                Message::Data {
-                    from: node_id.clone(),
-                    data: self.select_data(None, None).await?
+        if successor != node_id.clone() && predecessor.as_ref() != Some(&node_id) {
+            // 1. Transfer data to the successor, unless it is a virtual node on our own host
+            // and so already sees it in the shared store
+            let mut handover = host_of(&successor) != host_of(&node_id);
+            if handover {
+                let data = self.select_data(Some(start), Some(hash(&node_id))).await?;
+                if let Err(e) = Peer::new(&successor).transfer_keys(&node_id, data).await {
+                    // keep our copy, the successor still has the replicas of it
+                    log_message!(self, "Failed to hand keys over to {}: {}", successor, e);
+                    handover = false;
                 }
-            );
+            }
 
-            // 2. Notify the successor of the node's departure
-            let _ = send_post_request!(
-                &format!("http://{}/msg", successor),
-                Message::IAmYourPredecessor {
-                    node_id: predecessor.clone()
-                }
-            );
+            if let Some(predecessor) = predecessor {
+                // 2. Notify the successor of the node's departure
+                let _ = send_message!(
+                    &successor,
+                    Message::IAmYourPredecessor {
+                        node_id: predecessor.clone()
+                    }
+                );
 
-            // 3. Notify the predecessor of the node's departure
-            let _ = send_post_request!(
-                &format!("http://{}/msg", predecessor),
-                Message::IAmYourSuccessor {
-                    node_id: successor.clone()
-                }
-            );
+                // 3. Notify the predecessor of the node's departure
+                let _ = send_message!(
+                    &predecessor,
+                    Message::IAmYourSuccessor {
+                        node_id: successor.clone()
+                    }
+                );
+            }
+
+            self.gossip.leave(&node_id).await;
 
             // 4. Send leave message to the ChordRing
-            let chord_ring = CHORD_RING.lock().await.to_string();
-            let _ = send_post_request!(
-                &format!("http://{}/msg", chord_ring),
-                Message::Leave {
-                    node_id: node_id.clone()
-                }
-            );
+            notify_ring(Message::Leave {
+                node_id: node_id.clone(),
+            })
+            .await;
 
-            // 5. Clear the data in the node
-            self.db.lock().await.execute("DELETE FROM data", [])?;
+            // 5. Clear the data in the node, other virtual nodes keep the rest of the store
+            if handover {
+                self.remove_data(Some(start), Some(hash(&node_id))).await?;
+            }
+            if *VNODES == 1 {
+                self.replicas.lock().await.clear()?;
+            }
 
             // 6. Update node_state's successor and predecessor
-            // node_state.successor = Some(node_id.clone());
+            let mut node_state = self.node_state.lock().await;
             node_state.successor.clear();
             node_state.successor.insert_first(node_id.clone());
             node_state.predecessor = Some(node_id.clone());
             node_state.finger_table.clear();
+            drop(node_state);
 
             log_message!(self, "Node left the ring");
         } else {
@@ -374,99 +470,494 @@ impl Node {
         Ok(())
     }
 
-    pub async fn select_specific_data(&self, key: String) -> Result<Vec<Data>, rusqlite::Error> {
-        let conn = self.db.lock().await;
-        let mut stmt = conn.prepare("SELECT key, value FROM data WHERE key = ?")?;
-        let data_iter = stmt.query_map(params![key], |row| {
-            Ok(Data {
-                key: row.get(0)?,
-                value: row.get(1)?,
-            })
-        })?;
+    // A copy of the node's state to route and talk to other nodes with, so that the lock
+    // is never held across a network call. Changes are applied under a fresh lock afterwards.
+    pub async fn snapshot(&self) -> NodeState {
+        self.node_state.lock().await.clone()
+    }
+
+    pub fn metrics_report(&self) -> MetricsReport {
+        self.metrics.report(
+            self.tx.max_capacity() - self.tx.capacity(),
+            self.tx.max_capacity(),
+            *MAX_CONCURRENT_MESSAGES - self.workers.available_permits(),
+            *MAX_CONCURRENT_MESSAGES,
+        )
+    }
 
-        let mut data = Vec::new();
-        for d in data_iter {
-            data.push(d?);
+    // check_predecessor: forget a predecessor that stopped answering, so notify accepts
+    // whoever comes next, and serve its keys from our replicas meanwhile
+    async fn check_predecessor(&self) {
+        let ns = self.snapshot().await;
+        let pred = match ns.predecessor {
+            Some(pred) if pred != ns.id => pred,
+            _ => return,
+        };
+        if self.gossip.ping(&pred).await {
+            return;
         }
 
-        Ok(data)
+        let start = {
+            let mut ns = self.node_state.lock().await;
+            // a new predecessor notified us while we were pinging
+            if ns.predecessor.as_ref() != Some(&pred) {
+                return;
+            }
+            ns.predecessor = None;
+            ns.finger_table.remove_node(&pred);
+            ns.successor.remove_successor(&pred);
+            if ns.successor.get_first().is_none() {
+                // it was our only other node
+                let id = ns.id.clone();
+                ns.successor.insert_first(id);
+            }
+            closest_known_predecessor(&ns).map_or(hash(&ns.id), |id| hash(&id))
+        };
+        log_message!(self, "Predecessor {} is dead, clearing it", pred);
+        self.gossip.suspect(&pred).await;
+
+        match self.promote_replicas(start, hash(&ns.id)).await {
+            Ok(promoted) if !promoted.is_empty() => {
+                log_message!(
+                    self,
+                    "Took over {} keys of dead predecessor {}",
+                    promoted.len(),
+                    pred
+                );
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log_message!(self, "Failed to promote replicas: {}", e);
+            }
+        }
     }
 
-    // major bug fix
-    pub async fn select_data(
-        &self,
-        start_hash: Option<u32>,
-        end_hash: Option<u32>,
-    ) -> Result<Vec<Data>, rusqlite::Error> {
-        let conn = self.db.lock().await;
-        let mut stmt = match (start_hash, end_hash) {
-            (Some(start), Some(end)) => {
-                if start <= end {
-                    // Normal case - no wraparound
-                    conn.prepare("SELECT * FROM data WHERE hash > ? AND hash <= ? ORDER BY hash")?
-                } else {
-                    // Wraparound case - get data outside the excluded range
-                    conn.prepare("SELECT * FROM data WHERE hash > ? OR hash <= ? ORDER BY hash")?
+    // stabilize: adopt nodes that joined between us and our successor, drop dead successors,
+    // refresh the successor list from the first live one and notify it
+    async fn stabilize(&self) {
+        // A node that joined between us and our successor is now the successor's
+        // predecessor, and when alone our predecessor is our successor too
+        let ns = self.snapshot().await;
+        if let Some(succ) = ns.successor.get_first() {
+            let candidate = if *succ == ns.id {
+                ns.predecessor.clone()
+            } else {
+                Peer::new(succ).get_predecessor().await.ok().flatten()
+            };
+            if let Some(node) = candidate {
+                if node != ns.id
+                    && node != *succ
+                    && is_between(hash(&ns.id), hash(&node), hash(succ))
+                {
+                    let adopted = {
+                        let mut state = self.node_state.lock().await;
+                        // only if the successor didn't change while we were asking
+                        let unchanged = state.successor.get_first() == Some(succ);
+                        if unchanged {
+                            state.successor.push_front(node.clone());
+                        }
+                        unchanged
+                    };
+                    if adopted {
+                        log_message!(self, "Adopting {} as successor", node);
+                        self.gossip.discover(&node).await;
+                    }
                 }
             }
-            _ => conn.prepare("SELECT * FROM data ORDER BY hash")?,
-        };
+        }
+
+        // Skip if we're alone in the ring
+        let ns = self.snapshot().await;
+        if ns.successor.get_first() == Some(&ns.id) {
+            return;
+        }
+
+        // Check current successor list and remove dead nodes
+        let successors: Vec<String> = ns.successor.entries.iter().flatten().cloned().collect();
+        let mut prev_alive = ns.id.clone();
+        let mut dead = Vec::new();
+        for (i, succ) in successors.iter().enumerate() {
+            if self.gossip.ping(succ).await {
+                prev_alive = succ.clone();
+                continue;
+            }
+            log_message!(
+                self,
+                "Successor {} is dead, removing from successor list",
+                succ
+            );
 
-        let data_iter = {
-            let map_fn = |row: &rusqlite::Row| {
-                Ok(Data {
-                    key: row.get(1)?,
-                    value: row.get(2)?,
+            if let Some(next_succ) = successors.get(i + 1) {
+                // Notify the next successor that the last live node before it is its new predecessor
+                let _ = send_message!(
+                    &next_succ,
+                    Message::IAmYourPredecessor {
+                        node_id: prev_alive.clone()
+                    }
+                );
+
+                // Also notify the chord ring about the dead node
+                notify_ring(Message::Leave {
+                    node_id: succ.clone(),
                 })
-            };
+                .await;
+            }
 
-            match (start_hash, end_hash) {
-                (Some(start), Some(end)) => stmt.query_map(params![start, end], map_fn)?,
-                _ => stmt.query_map([], map_fn)?,
+            self.gossip.suspect(succ).await;
+            dead.push(succ.clone());
+        }
+
+        if !dead.is_empty() {
+            let mut ns = self.node_state.lock().await;
+            for succ in dead.iter() {
+                ns.successor.remove_successor(succ);
+            }
+            // If we lost every successor we are on our own until someone notifies us
+            if ns.successor.get_first().is_none() {
+                let id = ns.id.clone();
+                ns.successor.insert_first(id);
             }
+        }
+
+        // Update successor list with successors' successors
+        let immediate_succ = match self.node_state.lock().await.successor.get_first() {
+            Some(succ) if *succ != ns.id => succ.clone(),
+            _ => return,
         };
+        if let Ok(succ_list) = Peer::new(&immediate_succ).get_successor_list().await {
+            let mut new_successors = vec![Some(immediate_succ.clone())];
+
+            // Add successors' successors to our list
+            for succ in succ_list.into_iter().take(*N - 1) {
+                // Verify each successor is alive before adding
+                if self.gossip.ping(&succ).await {
+                    self.gossip.discover(&succ).await;
+                    new_successors.push(Some(succ));
+                }
+            }
+
+            // Pad with None if we don't have enough successors
+            while new_successors.len() < *N {
+                new_successors.push(None);
+            }
+
+            // Update successor list, unless it changed while we were asking around
+            let mut ns = self.node_state.lock().await;
+            if ns.successor.get_first() == Some(&immediate_succ) {
+                for (i, succ) in new_successors.into_iter().enumerate() {
+                    ns.successor.insert(i, succ);
+                }
+            }
+        }
+
+        // Notify our immediate successor that we may be its predecessor
+        let _ = Peer::new(&immediate_succ).notify(&ns.id).await;
+    }
 
-        let mut data = Vec::new();
-        for d in data_iter {
-            data.push(d?);
+    // Start a routed request at this node and wait for its response to come back
+    async fn start_request(
+        &self,
+        key: &str,
+        build: impl FnOnce(u64, String) -> Message,
+    ) -> Option<Message> {
+        let (id, rx) = self.pending.register().await;
+        let node_id = self.node_state.lock().await.id.clone();
+
+        if let Err(err) = self.tx.send(build(id, node_id)).await {
+            self.pending.cancel(id).await;
+            log_message!(self, "ERROR sending message: {}", err.to_string());
+            return None;
+        }
+
+        let timeout = std::time::Duration::from_secs(*REQUEST_TIMEOUT);
+        let res = self.pending.wait(id, rx, timeout).await;
+        if res.is_none() {
+            log_message!(self, "Request for key '{}' timed out", key);
         }
+        res
+    }
 
-        Ok(data)
+    pub async fn lookup(&self, key: String, query: Query) -> HttpResponse {
+        log_message!(self, "Lookup request for key '{}' started", key);
+        let res = self
+            .start_request(&key, |id, reply_to| Message::LookupReq {
+                id,
+                key: key.clone(),
+                hops: 0,
+                reply_to,
+                path: Vec::new(),
+                query: query.clone(),
+            })
+            .await;
+        lookup_reply(&key, &query, res)
+    }
+
+    pub async fn put(&self, data: Data, mode: WriteMode) -> HttpResponse {
+        let key = data.key.clone();
+        log_message!(self, "Put request for key '{}' started", key);
+        let res = self
+            .start_request(&key, |id, reply_to| Message::Put {
+                id,
+                data,
+                mode,
+                hops: 0,
+                reply_to,
+            })
+            .await;
+        write_reply(&key, res)
+    }
+
+    pub async fn delete(&self, key: String) -> HttpResponse {
+        log_message!(self, "Delete request for key '{}' started", key);
+        let res = self
+            .start_request(&key, |id, reply_to| Message::Delete {
+                id,
+                key: key.clone(),
+                hops: 0,
+                reply_to,
+            })
+            .await;
+        write_reply(&key, res)
+    }
+
+    pub async fn select_specific_data(&self, key: String) -> Result<Vec<Data>, StoreError> {
+        self.db.lock().await.get(&key)
+    }
+
+    // major bug fix
+    pub async fn select_data(
+        &self,
+        start_hash: Option<ChordId>,
+        end_hash: Option<ChordId>,
+    ) -> Result<Vec<Data>, StoreError> {
+        let db = self.db.lock().await;
+        match (start_hash, end_hash) {
+            (Some(start), Some(end)) => db.range(start, end),
+            _ => Ok(db.scan()?.into_iter().map(|(_, d)| d).collect()),
+        }
     }
 
     // major bug fix
     pub async fn remove_data(
         &self,
-        start_hash: Option<u32>,
-        end_hash: Option<u32>,
-    ) -> Result<(), rusqlite::Error> {
-        let conn = self.db.lock().await;
+        start_hash: Option<ChordId>,
+        end_hash: Option<ChordId>,
+    ) -> Result<(), StoreError> {
+        let mut db = self.db.lock().await;
         match (start_hash, end_hash) {
             (Some(start), Some(end)) => {
-                if start <= end {
-                    conn.execute(
-                        "DELETE FROM data WHERE hash > ? AND hash <= ?",
-                        params![start, end],
-                    )?
-                } else {
-                    conn.execute(
-                        "DELETE FROM data WHERE hash > ? OR hash <= ?",
-                        params![start, end],
-                    )?
-                }
+                db.remove_range(start, end)?;
             }
-            _ => conn.execute("DELETE FROM data", [])?,
+            _ => db.clear()?,
+        };
+
+        Ok(())
+    }
+
+    // Take over keys handed to us along with every version they hold, merged with ours
+    pub async fn merge_batch_data(&self, data: Vec<Data>) -> Result<(), StoreError> {
+        let (written, current) = {
+            let mut db = self.db.lock().await;
+            let written = db.put(&data, PutMode::Merge)?;
+            (written, versions_of(&**db, &data)?)
         };
 
+        self.report_siblings(&written.siblings).await;
+        self.replicate(current, Vec::new());
         Ok(())
     }
-    pub async fn insert_batch_data(&self, data: Vec<Data>) -> Result<(), rusqlite::Error> {
-        let conn = self.db.lock().await;
-        let mut stmt = conn.prepare("INSERT INTO data (key, value, hash) VALUES (?, ?, ?)")?;
-        for d in data {
-            stmt.execute(params![d.key, d.value, hash(&d.key) as u64])?;
+
+    pub async fn delete_specific_data(&self, key: String) -> Result<usize, StoreError> {
+        let removed = self.db.lock().await.delete(&key)?;
+
+        self.replicate(Vec::new(), vec![key]);
+        Ok(removed)
+    }
+
+    // Write a batch as `mode` says, all of it or, on an error, none of it. Returns the rows
+    // written, the others were skipped by keep_newest or append. The successors get every
+    // version of the keys written, so an append doesn't replace the values they already hold.
+    pub async fn insert_batch_data(
+        &self,
+        mut data: Vec<Data>,
+        mode: WriteMode,
+    ) -> Result<usize, StoreError> {
+        let node_id = self.node_state.lock().await.id.clone();
+        let (written, current) = {
+            let mut db = self.db.lock().await;
+            // The new version of a key has seen the versions its writer read, or every
+            // stored one when it didn't say, and one more write of ours
+            let mut clocks: HashMap<String, VectorClock> = HashMap::new();
+            for d in &mut data {
+                if !clocks.contains_key(&d.key) {
+                    let stored = db.get(&d.key)?;
+                    let stored: Vec<&VectorClock> = stored.iter().map(|s| &s.clock).collect();
+                    let mut context = d.clock.clone();
+                    if context.is_empty() {
+                        for clock in &stored {
+                            context.merge(clock);
+                        }
+                    }
+                    let clock = VectorClock::write(&context, &node_id, &stored);
+                    clocks.insert(d.key.clone(), clock);
+                }
+                d.clock = clocks[&d.key].clone();
+            }
+
+            let written = db.put(&data, mode.into())?;
+            let current = if written.rows > 0 {
+                versions_of(&**db, &data)?
+            } else {
+                Vec::new()
+            };
+            (written, current)
+        };
+
+        self.report_siblings(&written.siblings).await;
+        self.replicate(current, Vec::new());
+        Ok(written.rows)
+    }
+
+    async fn report_siblings(&self, keys: &[String]) {
+        for key in keys {
+            log_message!(self, "Key '{}' holds concurrent versions", key);
+        }
+    }
+
+    // Push primary copies (and deletions) to the next N-1 successors in the background,
+    // so callers holding the node_state lock don't block on it. Successors on our own
+    // host, or on a host we already picked, are skipped since they share a store.
+    pub fn replicate(&self, data: Vec<Data>, removed: Vec<String>) {
+        if data.is_empty() && removed.is_empty() {
+            return;
+        }
+
+        let node_state = self.node_state.clone();
+        tokio::spawn(async move {
+            let (node_id, successors) = {
+                let ns = node_state.lock().await;
+                let mut successors: Vec<String> = Vec::new();
+                for succ in ns.successor.entries.iter().flatten() {
+                    if successors.len() + 1 >= *N {
+                        break;
+                    }
+                    if host_of(succ) != host_of(&ns.id)
+                        && !successors.iter().any(|s| host_of(s) == host_of(succ))
+                    {
+                        successors.push(succ.clone());
+                    }
+                }
+                (ns.id.clone(), successors)
+            };
+
+            for succ in successors {
+                let _ = send_message!(
+                    &succ,
+                    Message::Replicate {
+                        from: node_id.clone(),
+                        data: data.clone(),
+                        removed: removed.clone()
+                    },
+                    peer_client::PEER_CLIENT.retry.max_retries,
+                    peer_client::PEER_CLIENT.bulk_timeout
+                );
+            }
+        });
+    }
+
+    pub async fn store_replicas(
+        &self,
+        data: Vec<Data>,
+        removed: Vec<String>,
+    ) -> Result<(), StoreError> {
+        let mut replicas = self.replicas.lock().await;
+        replicas.put(&data, PutMode::Merge)?;
+        for key in removed {
+            replicas.delete(&key)?;
         }
 
         Ok(())
     }
+
+    pub async fn select_replica_data(&self, key: String) -> Result<Vec<Data>, StoreError> {
+        self.replicas.lock().await.get(&key)
+    }
+
+    // Move primary copies in (start_hash, end_hash] into the replicas table, used when a
+    // restarted node finds persisted keys that now belong to someone else
+    pub async fn demote_data(
+        &self,
+        start_hash: ChordId,
+        end_hash: ChordId,
+    ) -> Result<usize, StoreError> {
+        let mut db = self.db.lock().await;
+        let demoted = db.range(start_hash, end_hash)?;
+        self.replicas.lock().await.put(&demoted, PutMode::Merge)?;
+        db.remove_range(start_hash, end_hash)
+    }
+
+    // Turn the replicas in (start_hash, end_hash] into primary copies now that we own that
+    // range, merged with any versions of the same keys we hold already. Returns the
+    // versions we now serve for those keys.
+    pub async fn promote_replicas(
+        &self,
+        start_hash: ChordId,
+        end_hash: ChordId,
+    ) -> Result<Vec<Data>, StoreError> {
+        let (written, promoted) = {
+            let mut db = self.db.lock().await;
+            let mut replicas = self.replicas.lock().await;
+            let promoted = replicas.range(start_hash, end_hash)?;
+            let written = db.put(&promoted, PutMode::Merge)?;
+            replicas.remove_range(start_hash, end_hash)?;
+            (written, versions_of(&**db, &promoted)?)
+        };
+
+        self.report_siblings(&written.siblings).await;
+        self.replicate(promoted.clone(), Vec::new());
+        Ok(promoted)
+    }
+}
+
+// Every version now stored of the keys in `data`
+fn versions_of(db: &dyn Store, data: &[Data]) -> Result<Vec<Data>, StoreError> {
+    let mut keys: Vec<&str> = data.iter().map(|d| d.key.as_str()).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let mut versions = Vec::new();
+    for key in keys {
+        versions.extend(db.get(key)?);
+    }
+    Ok(versions)
+}
+
+// The requests other nodes make of this one over POST /rpc
+impl ChordRpc for Node {
+    async fn find_successor(&self, id: ChordId) -> Result<Successor, RpcError> {
+        Ok(find_successor(&self.snapshot().await, id))
+    }
+
+    async fn get_predecessor(&self) -> Result<Option<String>, RpcError> {
+        Ok(self.node_state.lock().await.predecessor.clone())
+    }
+
+    async fn notify(&self, node_id: &str) -> Result<bool, RpcError> {
+        notify_handler(node_id.to_string(), self)
+            .await
+            .map_err(|e| RpcError::Failed(e.to_string()))
+    }
+
+    async fn get_successor_list(&self) -> Result<Vec<String>, RpcError> {
+        let ns = self.node_state.lock().await;
+        Ok(ns.successor.entries.iter().flatten().cloned().collect())
+    }
+
+    async fn transfer_keys(&self, from: &str, data: Vec<Data>) -> Result<usize, RpcError> {
+        log_message!(self, "Received {} keys from node {}", data.len(), from);
+        let keys = data.len();
+        self.merge_batch_data(data)
+            .await
+            .map_err(|e| RpcError::Failed(e.to_string()))?;
+        Ok(keys)
+    }
 }
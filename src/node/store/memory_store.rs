@@ -0,0 +1,119 @@
+use super::*;
+use std::collections::BTreeMap;
+
+// In-memory backend, entries grouped by hash so ranges are ordered scans, every value of
+// a key in the order it was written
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<ChordId, BTreeMap<String, Vec<Data>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn hashes_in(&self, start: ChordId, end: ChordId) -> Vec<ChordId> {
+        self.entries
+            .keys()
+            .filter(|h| is_between(start, **h, end))
+            .copied()
+            .collect()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Result<Vec<Data>, StoreError> {
+        Ok(self
+            .entries
+            .get(&hash(key))
+            .and_then(|bucket| bucket.get(key))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn put(&mut self, data: &[Data], mode: PutMode) -> Result<Written, StoreError> {
+        let plan = plan_put(data, mode, |key| self.get(key))?;
+        for (key, write) in plan.writes {
+            let (rows, replace) = match write {
+                KeyWrite::Skip => continue,
+                KeyWrite::Replace(rows) => (rows, true),
+                KeyWrite::Add(rows) => (rows, false),
+            };
+            let values = self
+                .entries
+                .entry(hash(&key))
+                .or_default()
+                .entry(key)
+                .or_default();
+            if replace {
+                values.clear();
+            }
+            values.extend(rows);
+        }
+
+        Ok(Written {
+            rows: plan.stored,
+            siblings: plan.siblings,
+        })
+    }
+
+    fn delete(&mut self, key: &str) -> Result<usize, StoreError> {
+        let h = hash(key);
+        let removed = match self.entries.get_mut(&h) {
+            Some(bucket) => bucket.remove(key).map_or(0, |values| values.len()),
+            None => 0,
+        };
+        if self.entries.get(&h).is_some_and(|bucket| bucket.is_empty()) {
+            self.entries.remove(&h);
+        }
+
+        Ok(removed)
+    }
+
+    fn range(&self, start: ChordId, end: ChordId) -> Result<Vec<Data>, StoreError> {
+        let mut data = Vec::new();
+        for h in self.hashes_in(start, end) {
+            data.extend(self.entries[&h].values().flatten().cloned());
+        }
+
+        Ok(data)
+    }
+
+    fn remove_range(&mut self, start: ChordId, end: ChordId) -> Result<usize, StoreError> {
+        let mut removed = 0;
+        for h in self.hashes_in(start, end) {
+            removed += self
+                .entries
+                .remove(&h)
+                .map_or(0, |bucket| bucket.values().map(Vec::len).sum());
+        }
+
+        Ok(removed)
+    }
+
+    fn scan(&self) -> Result<Vec<(ChordId, Data)>, StoreError> {
+        let mut data = Vec::new();
+        for (h, bucket) in &self.entries {
+            data.extend(bucket.values().flatten().map(|d| (*h, d.clone())));
+        }
+
+        Ok(data)
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        Ok(self
+            .entries
+            .values()
+            .flat_map(|bucket| bucket.values())
+            .map(Vec::len)
+            .sum())
+    }
+
+    fn clear(&mut self) -> Result<(), StoreError> {
+        self.entries.clear();
+        Ok(())
+    }
+}
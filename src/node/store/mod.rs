@@ -0,0 +1,661 @@
+use super::*;
+pub mod memory_store;
+pub mod sqlite_store;
+
+pub use memory_store::*;
+pub use sqlite_store::*;
+
+// How a write treats a key that is already stored. A key may hold several values (see
+// Append) and, after concurrent writes, several versions of them (see VectorClock).
+// The writes of clients come with the clock of their new version and only replace the
+// versions that clock descends, the others stay as siblings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PutMode {
+    // fail on an existing key, or one that comes twice in the batch
+    Insert,
+    // replace the stored values, the last incoming row wins
+    Overwrite,
+    // replace the stored values with the newest incoming row, if it is newer than all of them
+    KeepNewest,
+    // keep the stored values and add the incoming ones that aren't stored yet
+    Append,
+    // reconcile the versions of another node with ours, used for replicas and transfers
+    // which carry every version of a key: a version that another one descends is dropped,
+    // concurrent ones are kept side by side
+    Merge,
+}
+
+impl From<WriteMode> for PutMode {
+    fn from(mode: WriteMode) -> Self {
+        match mode {
+            WriteMode::InsertOnly => PutMode::Insert,
+            WriteMode::Overwrite => PutMode::Overwrite,
+            WriteMode::KeepNewest => PutMode::KeepNewest,
+            WriteMode::Append => PutMode::Append,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    // the keys of an Insert that already exist, nothing of the batch was written
+    Conflict(Vec<String>),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "{}", e),
+            StoreError::Conflict(keys) if keys.len() == 1 => {
+                write!(f, "key '{}' already exists", keys[0])
+            }
+            StoreError::Conflict(keys) => write!(
+                f,
+                "{} keys already exist: '{}'",
+                keys.len(),
+                keys.join("', '")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+// What a put does to one key
+#[derive(Debug)]
+pub enum KeyWrite {
+    Skip,
+    // drop the stored values and write these
+    Replace(Vec<Data>),
+    // write these next to the stored values
+    Add(Vec<Data>),
+}
+
+#[derive(Debug, Default)]
+pub struct PutPlan {
+    pub writes: Vec<(String, KeyWrite)>,
+    // incoming rows that made it into the store, the others were skipped
+    pub stored: usize,
+    // keys left holding concurrent versions
+    pub siblings: Vec<String>,
+}
+
+// What a put wrote
+#[derive(Debug, Default)]
+pub struct Written {
+    // incoming rows stored, an append also rewrites the values it keeps
+    pub rows: usize,
+    pub siblings: Vec<String>,
+}
+
+// Work out what a put does to each key of the batch, in the order the keys first appear,
+// given a way to read what a key holds now. Both backends plan the whole batch before
+// writing anything, so an Insert conflict leaves the store untouched.
+pub fn plan_put(
+    data: &[Data],
+    mode: PutMode,
+    mut stored: impl FnMut(&str) -> Result<Vec<Data>, StoreError>,
+) -> Result<PutPlan, StoreError> {
+    let mut groups: Vec<(String, Vec<Data>)> = Vec::new();
+    let mut index = std::collections::HashMap::new();
+    for d in data {
+        let at = *index.entry(d.key.clone()).or_insert_with(|| {
+            groups.push((d.key.clone(), Vec::new()));
+            groups.len() - 1
+        });
+        groups[at].1.push(d.clone());
+    }
+
+    let mut conflicts = Vec::new();
+    let mut plan = PutPlan::default();
+    for (key, mut incoming) in groups {
+        let current = stored(&key)?;
+        let rows = incoming.len();
+        let (write, applied) = match mode {
+            PutMode::Insert => {
+                if !current.is_empty() || incoming.len() > 1 {
+                    conflicts.push(key.clone());
+                }
+                (KeyWrite::Add(incoming), rows)
+            }
+            PutMode::Merge => match merge_versions(current, incoming) {
+                KeyWrite::Skip => (KeyWrite::Skip, 0),
+                write => (write, rows),
+            },
+            PutMode::Overwrite | PutMode::KeepNewest | PutMode::Append => {
+                let clock = incoming[0].clock.clone();
+                let (superseded, siblings): (Vec<Data>, Vec<Data>) =
+                    current.into_iter().partition(|d| clock.descends(&d.clock));
+                let written = match mode {
+                    PutMode::Overwrite => Some((incoming.split_off(incoming.len() - 1), 1)),
+                    PutMode::KeepNewest => {
+                        // the first of equally new rows, and on a tie the stored value stays
+                        let newest = incoming
+                            .into_iter()
+                            .rev()
+                            .max_by_key(|d| d.timestamp)
+                            .expect("groups are never empty");
+                        match superseded.iter().map(|d| d.timestamp).max() {
+                            Some(stored) if stored >= newest.timestamp => None,
+                            _ => Some((vec![newest], 1)),
+                        }
+                    }
+                    _ => {
+                        // the stored values move on to the new version
+                        let kept = superseded.len();
+                        let mut rows = superseded;
+                        for row in &mut rows {
+                            row.clock = clock.clone();
+                        }
+                        for d in incoming {
+                            if !rows.iter().any(|r| r.value == d.value) {
+                                rows.push(d);
+                            }
+                        }
+                        let added = rows.len() - kept;
+                        (added > 0).then_some((rows, added))
+                    }
+                };
+                match written {
+                    Some((mut rows, applied)) => {
+                        rows.extend(siblings);
+                        (KeyWrite::Replace(rows), applied)
+                    }
+                    None => (KeyWrite::Skip, 0),
+                }
+            }
+        };
+        plan.stored += applied;
+        if let KeyWrite::Replace(rows) = &write {
+            if rows.iter().any(|r| r.clock != rows[0].clock) {
+                plan.siblings.push(key.clone());
+            }
+        }
+        plan.writes.push((key, write));
+    }
+
+    if !conflicts.is_empty() {
+        return Err(StoreError::Conflict(conflicts));
+    }
+    Ok(plan)
+}
+
+// Every version of a key known to us or to the sender that no other version descends.
+// An incoming version takes the place of a stored one with the same clock.
+fn merge_versions(current: Vec<Data>, incoming: Vec<Data>) -> KeyWrite {
+    let mut rows: Vec<Data> = current
+        .iter()
+        .filter(|d| !incoming.iter().any(|i| i.clock == d.clock))
+        .cloned()
+        .collect();
+    rows.extend(incoming);
+    let clocks: Vec<VectorClock> = rows.iter().map(|d| d.clock.clone()).collect();
+    rows.retain(|d| {
+        !clocks
+            .iter()
+            .any(|clock| *clock != d.clock && clock.descends(&d.clock))
+    });
+
+    // the same versions with the same values, in whatever order the store returned them
+    let mut unmatched = current;
+    let unchanged = rows.iter().all(|row| {
+        let found = unmatched
+            .iter()
+            .position(|d| d.clock == row.clock && d.value == row.value);
+        found.map(|at| unmatched.swap_remove(at)).is_some()
+    }) && unmatched.is_empty();
+
+    if unchanged {
+        KeyWrite::Skip
+    } else {
+        KeyWrite::Replace(rows)
+    }
+}
+
+// Key/value storage behind a Node. Ranges are (start, end] on the ring and wrap around
+// like is_between, so start == end covers every key.
+pub trait Store: Send + std::fmt::Debug {
+    fn get(&self, key: &str) -> Result<Vec<Data>, StoreError>;
+    // Applies the whole batch or none of it
+    fn put(&mut self, data: &[Data], mode: PutMode) -> Result<Written, StoreError>;
+    fn delete(&mut self, key: &str) -> Result<usize, StoreError>;
+    fn range(&self, start: ChordId, end: ChordId) -> Result<Vec<Data>, StoreError>;
+    fn remove_range(&mut self, start: ChordId, end: ChordId) -> Result<usize, StoreError>;
+    // every entry with its hash, ordered by hash
+    fn scan(&self) -> Result<Vec<(ChordId, Data)>, StoreError>;
+    fn count(&self) -> Result<usize, StoreError>;
+    fn clear(&mut self) -> Result<(), StoreError>;
+}
+
+// A store shared by all virtual nodes of one process
+pub type SharedStore = Arc<Mutex<Box<dyn Store>>>;
+
+// Opens the primary and replica stores for a node, using the backend picked by STORE.
+// Both sqlite stores are tables of the one database open_db gives the node.
+pub fn open_stores(node_id: &str) -> (Box<dyn Store>, Box<dyn Store>) {
+    match STORE.as_str() {
+        "memory" => (Box::new(MemoryStore::new()), Box::new(MemoryStore::new())),
+        backend => {
+            if backend != "sqlite" {
+                eprintln!(
+                    "Unknown STORE backend '{}', expected sqlite or memory. Using sqlite",
+                    backend
+                );
+            }
+            (
+                Box::new(SqliteStore::new(open_db(node_id), "data")),
+                Box::new(SqliteStore::new(open_db(node_id), "replicas")),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqlite_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../../../misc/schema.sql"))
+            .unwrap();
+        conn
+    }
+
+    fn sqlite_store() -> SqliteStore {
+        SqliteStore::new(sqlite_conn(), "data")
+    }
+
+    fn row(key: &str, value: &str, timestamp: i64) -> Data {
+        let mut data = Data::new(key, value);
+        data.timestamp = timestamp;
+        data
+    }
+
+    fn values(store: &dyn Store, key: &str) -> Vec<String> {
+        store
+            .get(key)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.value)
+            .collect()
+    }
+
+    fn filled(store: &mut dyn Store) -> Vec<Data> {
+        let data: Vec<Data> = (0..40)
+            .map(|i| Data::new(&format!("key{}", i), "value"))
+            .collect();
+        store.put(&data, PutMode::Overwrite).unwrap();
+        data
+    }
+
+    fn keys(data: &[Data]) -> Vec<String> {
+        let mut keys: Vec<String> = data.iter().map(|d| d.key.clone()).collect();
+        keys.sort();
+        keys
+    }
+
+    // the keys whose hash is at most `end` steps clockwise past `start`, but not at it,
+    // or every key when start == end
+    fn expected(data: &[Data], start: ChordId, end: ChordId) -> Vec<String> {
+        let width = end.wrapping_sub(start);
+        let inside: Vec<Data> = data
+            .iter()
+            .filter(|d| {
+                let steps = hash(&d.key).wrapping_sub(start);
+                width == ChordId::default() || (steps != ChordId::default() && steps <= width)
+            })
+            .cloned()
+            .collect();
+        keys(&inside)
+    }
+
+    // (start, end] pairs: in order, wrapping past zero, at the ends of the ring, and
+    // start == end for the whole ring
+    fn ranges(data: &[Data]) -> Vec<(ChordId, ChordId)> {
+        let zero = ChordId::default();
+        let last = zero.wrapping_sub(ChordId::pow2(0));
+        let (low, high) = {
+            let (a, b) = (hash(&data[3].key), hash(&data[7].key));
+            (a.min(b), a.max(b))
+        };
+        vec![
+            (low, high),
+            (high, low),
+            (last, high),
+            (high, zero),
+            (zero, last),
+            (low, low),
+            (zero, zero),
+        ]
+    }
+
+    fn check_ranges(mut open: impl FnMut() -> Box<dyn Store>) {
+        let mut store = open();
+        let data = filled(store.as_mut());
+        for (start, end) in ranges(&data) {
+            assert_eq!(
+                keys(&store.range(start, end).unwrap()),
+                expected(&data, start, end),
+                "range ({}, {}]",
+                start,
+                end
+            );
+        }
+        assert_eq!(
+            store
+                .range(ChordId::default(), ChordId::default())
+                .unwrap()
+                .len(),
+            40
+        );
+
+        for (start, end) in ranges(&data) {
+            let mut store = open();
+            filled(store.as_mut());
+            let removed = expected(&data, start, end);
+            assert_eq!(store.remove_range(start, end).unwrap(), removed.len());
+            let left: Vec<Data> = store.scan().unwrap().into_iter().map(|(_, d)| d).collect();
+            let mut all = keys(&left);
+            all.extend(removed);
+            all.sort();
+            assert_eq!(all, keys(&data), "remove_range ({}, {}]", start, end);
+        }
+    }
+
+    // An INTEGER hash column (files from before ChordId) reads all-digit hex ids back
+    // as numbers unless the table is rebuilt
+    #[test]
+    fn sqlite_rebuilds_integer_hash_column() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE data (hash INTEGER, key TEXT, value TEXT,
+                 timestamp INTEGER NOT NULL DEFAULT 0, clock TEXT NOT NULL DEFAULT '{}');",
+        )
+        .unwrap();
+        for i in 0..40 {
+            conn.execute(
+                "INSERT INTO data (hash, key, value) VALUES (?, ?, 'value')",
+                params![i, format!("key{}", i)],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteStore::new(conn, "data");
+        let stored = store.scan().unwrap();
+        assert_eq!(stored.len(), 40);
+        for (h, d) in stored {
+            assert_eq!(h, hash(&d.key));
+        }
+    }
+
+    #[test]
+    fn insert_conflicts_leave_the_store_untouched() {
+        let mut store = MemoryStore::new();
+        store.put(&[row("a", "1", 1)], PutMode::Insert).unwrap();
+
+        let batch = [row("b", "2", 1), row("a", "3", 1), row("c", "4", 1)];
+        match store.put(&batch, PutMode::Insert) {
+            Err(StoreError::Conflict(keys)) => assert_eq!(keys, vec!["a"]),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        let batch = [row("d", "5", 1), row("d", "6", 1)];
+        match store.put(&batch, PutMode::Insert) {
+            Err(StoreError::Conflict(keys)) => assert_eq!(keys, vec!["d"]),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(store.count().unwrap(), 1);
+        assert_eq!(values(&store, "a"), vec!["1"]);
+        assert!(values(&store, "b").is_empty());
+    }
+
+    #[test]
+    fn keep_newest_keeps_the_stored_value_on_a_tie() {
+        let mut store = MemoryStore::new();
+        store
+            .put(&[row("a", "old", 100)], PutMode::Overwrite)
+            .unwrap();
+
+        let written = store
+            .put(&[row("a", "tie", 100)], PutMode::KeepNewest)
+            .unwrap();
+        assert_eq!(written.rows, 0);
+        let written = store
+            .put(&[row("a", "older", 50)], PutMode::KeepNewest)
+            .unwrap();
+        assert_eq!(written.rows, 0);
+        assert_eq!(values(&store, "a"), vec!["old"]);
+
+        let batch = [row("a", "newer", 150), row("a", "newest", 200)];
+        let written = store.put(&batch, PutMode::KeepNewest).unwrap();
+        assert_eq!(written.rows, 1);
+        assert_eq!(values(&store, "a"), vec!["newest"]);
+    }
+
+    #[test]
+    fn append_adds_only_new_values() {
+        let mut store = MemoryStore::new();
+        store.put(&[row("a", "x", 1)], PutMode::Append).unwrap();
+
+        let batch = [row("a", "x", 2), row("a", "y", 2), row("a", "y", 3)];
+        let written = store.put(&batch, PutMode::Append).unwrap();
+        assert_eq!(written.rows, 1);
+        assert_eq!(values(&store, "a"), vec!["x", "y"]);
+
+        let written = store.put(&[row("a", "y", 4)], PutMode::Append).unwrap();
+        assert_eq!(written.rows, 0);
+        assert_eq!(store.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn sqlite_rolls_back_a_failed_batch() {
+        let conn = sqlite_conn();
+        conn.execute_batch(
+            "CREATE TRIGGER fail BEFORE INSERT ON data WHEN NEW.key = 'bad'
+             BEGIN SELECT RAISE(ABORT, 'bad key'); END;",
+        )
+        .unwrap();
+        let mut store = SqliteStore::new(conn, "data");
+        store
+            .put(&[row("a", "before", 1)], PutMode::Overwrite)
+            .unwrap();
+
+        let batch = [row("a", "after", 2), row("b", "1", 2), row("bad", "2", 2)];
+        assert!(matches!(
+            store.put(&batch, PutMode::Overwrite),
+            Err(StoreError::Sqlite(_))
+        ));
+        assert_eq!(values(&store, "a"), vec!["before"]);
+        assert!(values(&store, "b").is_empty());
+        assert_eq!(store.count().unwrap(), 1);
+    }
+
+    // A client write as the node makes it: its version has seen the context it read, or
+    // every stored version without one
+    fn versioned(store: &dyn Store, value: &str, context: Option<&VectorClock>) -> Data {
+        let stored = store.get("k").unwrap();
+        let clocks: Vec<&VectorClock> = stored.iter().map(|d| &d.clock).collect();
+        let context = match context {
+            Some(context) => context.clone(),
+            None => {
+                let mut context = VectorClock::default();
+                for clock in &clocks {
+                    context.merge(clock);
+                }
+                context
+            }
+        };
+        let mut data = Data::new("k", value);
+        data.clock = VectorClock::write(&context, "node", &clocks);
+        data
+    }
+
+    fn versions(store: &dyn Store) -> Vec<Data> {
+        let mut versions = store.get("k").unwrap();
+        versions.sort_by(|a, b| a.value.cmp(&b.value));
+        versions
+    }
+
+    #[test]
+    fn a_stale_context_keeps_a_sibling() {
+        let mut store = MemoryStore::new();
+        let first = versioned(&store, "1", None);
+        store
+            .put(std::slice::from_ref(&first), PutMode::Overwrite)
+            .unwrap();
+        let second = versioned(&store, "2", Some(&first.clock));
+        store.put(&[second], PutMode::Overwrite).unwrap();
+        assert_eq!(values(&store, "k"), vec!["2"]);
+
+        // written on top of the first version, after the second replaced it
+        let stale = versioned(&store, "3", Some(&first.clock));
+        let written = store.put(&[stale], PutMode::Overwrite).unwrap();
+        assert_eq!(written.siblings, vec!["k"]);
+        let kept: Vec<String> = versions(&store).into_iter().map(|d| d.value).collect();
+        assert_eq!(kept, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn a_write_replaces_exactly_the_versions_it_read() {
+        let mut store = MemoryStore::new();
+        let first = versioned(&store, "1", None);
+        store
+            .put(std::slice::from_ref(&first), PutMode::Overwrite)
+            .unwrap();
+        let second = versioned(&store, "2", Some(&first.clock));
+        store
+            .put(std::slice::from_ref(&second), PutMode::Overwrite)
+            .unwrap();
+        let stale = versioned(&store, "3", Some(&first.clock));
+        store
+            .put(std::slice::from_ref(&stale), PutMode::Overwrite)
+            .unwrap();
+
+        // read only the second version
+        let write = versioned(&store, "4", Some(&second.clock));
+        store.put(&[write], PutMode::Overwrite).unwrap();
+        let kept: Vec<String> = versions(&store).into_iter().map(|d| d.value).collect();
+        assert_eq!(kept, vec!["3", "4"]);
+
+        // read both that are left
+        let mut context = VectorClock::default();
+        for d in store.get("k").unwrap() {
+            context.merge(&d.clock);
+        }
+        let write = versioned(&store, "5", Some(&context));
+        let written = store.put(&[write], PutMode::Overwrite).unwrap();
+        assert!(written.siblings.is_empty());
+        assert_eq!(values(&store, "k"), vec!["5"]);
+    }
+
+    #[test]
+    fn a_blind_write_supersedes_every_sibling() {
+        let mut store = MemoryStore::new();
+        let first = versioned(&store, "1", None);
+        store
+            .put(std::slice::from_ref(&first), PutMode::Overwrite)
+            .unwrap();
+        for value in ["2", "3"] {
+            let write = versioned(&store, value, Some(&first.clock));
+            store.put(&[write], PutMode::Overwrite).unwrap();
+        }
+        assert_eq!(store.get("k").unwrap().len(), 2);
+
+        let blind = versioned(&store, "4", None);
+        let written = store.put(&[blind], PutMode::Overwrite).unwrap();
+        assert!(written.siblings.is_empty());
+        assert_eq!(values(&store, "k"), vec!["4"]);
+    }
+
+    #[test]
+    fn merging_the_same_versions_twice_changes_nothing() {
+        let mut store = MemoryStore::new();
+        let first = versioned(&store, "1", None);
+        store
+            .put(std::slice::from_ref(&first), PutMode::Overwrite)
+            .unwrap();
+        let second = versioned(&store, "2", Some(&first.clock));
+        store.put(&[second], PutMode::Overwrite).unwrap();
+        let stale = versioned(&store, "3", Some(&first.clock));
+        store.put(&[stale], PutMode::Overwrite).unwrap();
+        let siblings = store.get("k").unwrap();
+
+        // a replica gets them once, then again, then an old version that both descend
+        let mut replica = MemoryStore::new();
+        assert!(matches!(
+            merge_versions(Vec::new(), siblings.clone()),
+            KeyWrite::Replace(rows) if rows == siblings
+        ));
+        replica.put(&siblings, PutMode::Merge).unwrap();
+        assert!(matches!(
+            merge_versions(replica.get("k").unwrap(), siblings.clone()),
+            KeyWrite::Skip
+        ));
+        assert!(matches!(
+            merge_versions(replica.get("k").unwrap(), vec![first]),
+            KeyWrite::Skip
+        ));
+        // in whatever order the store hands them back or the sender lists them
+        let mut reversed = siblings.clone();
+        reversed.reverse();
+        assert!(matches!(
+            merge_versions(reversed.clone(), siblings.clone()),
+            KeyWrite::Skip
+        ));
+        for sibling in &siblings {
+            assert!(matches!(
+                merge_versions(siblings.clone(), vec![sibling.clone()]),
+                KeyWrite::Skip
+            ));
+        }
+        // a version with the same clock but another value is still a change
+        let mut changed = siblings[0].clone();
+        changed.value = "5".to_string();
+        assert!(matches!(
+            merge_versions(reversed, vec![changed]),
+            KeyWrite::Replace(rows) if rows.len() == 2
+        ));
+        let written = replica.put(&siblings, PutMode::Merge).unwrap();
+        assert_eq!(written.rows, 0);
+        assert_eq!(versions(&replica), versions(&store));
+    }
+
+    #[test]
+    fn memory_ranges_wrap_around() {
+        check_ranges(|| Box::new(MemoryStore::new()));
+    }
+
+    #[test]
+    fn sqlite_ranges_wrap_around() {
+        check_ranges(|| Box::new(sqlite_store()));
+    }
+
+    #[test]
+    fn in_memory_stores_of_a_node_share_one_database() {
+        let (mut data, mut replicas) = open_stores("127.0.0.1:9101");
+        data.put(&[row("a", "1", 1)], PutMode::Overwrite).unwrap();
+        replicas
+            .put(&[row("b", "2", 1)], PutMode::Overwrite)
+            .unwrap();
+
+        let conn = open_db("127.0.0.1:9101");
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |r| r.get(0))
+                .unwrap()
+        };
+        assert_eq!((count("data"), count("replicas")), (1, 1));
+
+        // another node's database is its own
+        let other = open_db("127.0.0.1:9102");
+        let rows: i64 = other
+            .query_row("SELECT COUNT(*) FROM data", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+}
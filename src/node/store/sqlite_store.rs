@@ -0,0 +1,253 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+    table: &'static str,
+}
+
+// Ids are stored as fixed-width hex so SQLite's text ordering matches the ring ordering
+impl rusqlite::ToSql for ChordId {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(rusqlite::types::ToSqlOutput::from(self.to_hex()))
+    }
+}
+
+impl rusqlite::types::FromSql for ChordId {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        let s = value.as_str()?;
+        ChordId::from_hex(s).ok_or_else(|| rusqlite::types::FromSqlError::Other(s.into()))
+    }
+}
+
+impl SqliteStore {
+    pub fn new(conn: Connection, table: &'static str) -> Self {
+        let store = SqliteStore { conn, table };
+        store.migrate().expect("Failed to migrate the stored keys");
+        store.rehash().expect("Failed to rehash stored keys");
+        store
+    }
+
+    // Files from before ChordId have an INTEGER hash column, whose affinity turns every
+    // all-digit hex id written to it back into a number, and files written before keys
+    // could hold several values have `key` as the primary key and no timestamp column.
+    // Rebuild such a table in the current shape (the old rows get timestamp 0, older than
+    // any new write, and rehash fixes their hashes). Files from before versions lack the
+    // clock column, their rows get an empty clock which any written version descends.
+    // Also creates the indexes, which are left out of schema.sql since they would attach
+    // to the old table.
+    fn migrate(&self) -> Result<(), StoreError> {
+        let (keyed, columns) = {
+            let mut stmt = self
+                .conn
+                .prepare(&format!("PRAGMA table_info({})", self.table))?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(5)?,
+                ))
+            })?;
+            let mut keyed = false;
+            let mut columns = Vec::new();
+            for row in rows {
+                let (name, kind, pk) = row?;
+                keyed |= pk > 0;
+                columns.push((name, kind));
+            }
+            (keyed, columns)
+        };
+        let has = |name: &str| columns.iter().any(|(c, _)| c == name);
+        let text_hash = columns
+            .iter()
+            .any(|(c, kind)| c == "hash" && kind.eq_ignore_ascii_case("TEXT"));
+
+        let table = self.table;
+        if keyed || !text_hash || !has("timestamp") {
+            let timestamp = if has("timestamp") { "timestamp" } else { "0" };
+            let clock = if has("clock") { "clock" } else { "'{}'" };
+            self.conn.execute_batch(&format!(
+                "BEGIN;
+                 ALTER TABLE {table} RENAME TO {table}_old;
+                 CREATE TABLE {table} (
+                     hash TEXT,
+                     key TEXT,
+                     value TEXT,
+                     timestamp INTEGER NOT NULL DEFAULT 0,
+                     clock TEXT NOT NULL DEFAULT '{{}}'
+                 );
+                 INSERT INTO {table} (hash, key, value, timestamp, clock)
+                     SELECT hash, key, value, {timestamp}, {clock} FROM {table}_old;
+                 DROP TABLE {table}_old;
+                 COMMIT;"
+            ))?;
+        } else if !has("clock") {
+            self.conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN clock TEXT NOT NULL DEFAULT '{{}}';"
+            ))?;
+        }
+        self.conn.execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS {table}_key ON {table} (key);
+             CREATE INDEX IF NOT EXISTS {table}_hash ON {table} (hash);"
+        ))?;
+
+        Ok(())
+    }
+
+    // A persisted file may come from a run with a different M (or the old integer
+    // hash column), so recompute every stored hash before using it
+    fn rehash(&self) -> Result<(), StoreError> {
+        let keys = {
+            let sql = format!("SELECT key, hash FROM {}", self.table);
+            let mut stmt = self.conn.prepare(&sql)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, rusqlite::types::Value>(1)?,
+                ))
+            })?;
+            let mut keys = Vec::new();
+            for row in rows {
+                let (key, stored) = row?;
+                let expected = hash(&key);
+                if stored != rusqlite::types::Value::Text(expected.to_hex()) {
+                    keys.push((key, expected));
+                }
+            }
+            keys
+        };
+
+        let sql = format!("UPDATE {} SET hash = ? WHERE key = ?", self.table);
+        for (key, expected) in keys {
+            self.conn.execute(&sql, params![expected, key])?;
+        }
+
+        Ok(())
+    }
+
+    fn range_clause(start: ChordId, end: ChordId) -> &'static str {
+        if start < end {
+            "hash > ?1 AND hash <= ?2"
+        } else {
+            "hash > ?1 OR hash <= ?2"
+        }
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        args: impl rusqlite::Params,
+    ) -> Result<Vec<(ChordId, Data)>, StoreError> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let data_iter = stmt.query_map(args, |row| {
+            Ok((
+                row.get(0)?,
+                Data {
+                    key: row.get(1)?,
+                    value: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    clock: row.get(4)?,
+                },
+            ))
+        })?;
+
+        let mut data = Vec::new();
+        for d in data_iter {
+            data.push(d?);
+        }
+
+        Ok(data)
+    }
+}
+
+impl Store for SqliteStore {
+    fn get(&self, key: &str) -> Result<Vec<Data>, StoreError> {
+        let sql = format!(
+            "SELECT hash, key, value, timestamp, clock FROM {} WHERE key = ? ORDER BY rowid",
+            self.table
+        );
+        Ok(self
+            .query(&sql, params![key])?
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect())
+    }
+
+    fn put(&mut self, data: &[Data], mode: PutMode) -> Result<Written, StoreError> {
+        let plan = plan_put(data, mode, |key| self.get(key))?;
+        let tx = self.conn.transaction()?;
+        {
+            let mut delete = tx.prepare(&format!("DELETE FROM {} WHERE key = ?", self.table))?;
+            let mut insert = tx.prepare(&format!(
+                "INSERT INTO {} (hash, key, value, timestamp, clock) VALUES (?, ?, ?, ?, ?)",
+                self.table
+            ))?;
+            for (key, write) in plan.writes {
+                let rows = match write {
+                    KeyWrite::Skip => continue,
+                    KeyWrite::Replace(rows) => {
+                        delete.execute(params![key])?;
+                        rows
+                    }
+                    KeyWrite::Add(rows) => rows,
+                };
+                for d in &rows {
+                    insert.execute(params![hash(&d.key), d.key, d.value, d.timestamp, d.clock])?;
+                }
+            }
+        }
+        // dropping the transaction on an error above rolls the whole batch back
+        tx.commit()?;
+
+        Ok(Written {
+            rows: plan.stored,
+            siblings: plan.siblings,
+        })
+    }
+
+    fn delete(&mut self, key: &str) -> Result<usize, StoreError> {
+        let sql = format!("DELETE FROM {} WHERE key = ?", self.table);
+        Ok(self.conn.execute(&sql, params![key])?)
+    }
+
+    fn range(&self, start: ChordId, end: ChordId) -> Result<Vec<Data>, StoreError> {
+        let sql = format!(
+            "SELECT hash, key, value, timestamp, clock FROM {} WHERE {} ORDER BY hash, rowid",
+            self.table,
+            Self::range_clause(start, end)
+        );
+        Ok(self
+            .query(&sql, params![start, end])?
+            .into_iter()
+            .map(|(_, d)| d)
+            .collect())
+    }
+
+    fn remove_range(&mut self, start: ChordId, end: ChordId) -> Result<usize, StoreError> {
+        let sql = format!(
+            "DELETE FROM {} WHERE {}",
+            self.table,
+            Self::range_clause(start, end)
+        );
+        Ok(self.conn.execute(&sql, params![start, end])?)
+    }
+
+    fn scan(&self) -> Result<Vec<(ChordId, Data)>, StoreError> {
+        let sql = format!(
+            "SELECT hash, key, value, timestamp, clock FROM {} ORDER BY hash, rowid",
+            self.table
+        );
+        self.query(&sql, [])
+    }
+
+    fn count(&self) -> Result<usize, StoreError> {
+        let sql = format!("SELECT COUNT(*) FROM {}", self.table);
+        Ok(self.conn.query_row(&sql, [], |row| row.get(0))?)
+    }
+
+    fn clear(&mut self) -> Result<(), StoreError> {
+        self.conn
+            .execute(&format!("DELETE FROM {}", self.table), [])?;
+        Ok(())
+    }
+}
@@ -35,6 +35,13 @@ impl SuccTable {
         self.entries[0] = Some(id);
     }
 
+    // Put a closer successor in front, shifting the others back
+    pub fn push_front(&mut self, id: String) {
+        self.remove_successor(&id);
+        self.entries.pop();
+        self.entries.insert(0, Some(id));
+    }
+
     pub fn get_first(&self) -> Option<&String> {
         self.entries[0].as_ref()
     }
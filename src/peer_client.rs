@@ -0,0 +1,124 @@
+use crate::{
+    PEER_BACKOFF, PEER_BULK_TIMEOUT, PEER_CONNECT_TIMEOUT, PEER_REQUEST_TIMEOUT, PEER_RETRIES,
+};
+use rand::Rng;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    // every node and the ChordRing talk to their peers through this one client
+    pub static ref PEER_CLIENT: PeerClient = PeerClient::new(
+        Duration::from_millis(*PEER_CONNECT_TIMEOUT),
+        Duration::from_millis(*PEER_REQUEST_TIMEOUT),
+        Duration::from_millis(*PEER_BULK_TIMEOUT),
+        RetryPolicy {
+            max_retries: *PEER_RETRIES,
+            base_delay: Duration::from_millis(*PEER_BACKOFF),
+            max_delay: Duration::from_secs(2),
+        },
+    );
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // attempts in total, the first one included
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Full jitter: a random wait of up to base_delay * 2^attempt, so nodes that failed
+    // together don't retry together
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        cap.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+// A pooled HTTP client for the traffic between nodes. Connections to a peer are kept alive
+// and reused instead of being set up for every message, and a peer that hangs costs at most
+// the timeout of the request instead of blocking the caller.
+#[derive(Debug, Clone)]
+pub struct PeerClient {
+    client: Client,
+    // how long a request may take unless its caller picks another timeout
+    pub request_timeout: Duration,
+    // the timeout callers pick for requests carrying a batch of keys
+    pub bulk_timeout: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl PeerClient {
+    pub fn new(
+        connect_timeout: Duration,
+        request_timeout: Duration,
+        bulk_timeout: Duration,
+        retry: RetryPolicy,
+    ) -> Self {
+        let client = Client::builder()
+            .connect_timeout(connect_timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_nodelay(true)
+            .build()
+            .unwrap();
+        PeerClient {
+            client,
+            request_timeout,
+            bulk_timeout,
+            retry,
+        }
+    }
+
+    pub async fn post<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> reqwest::Result<Response> {
+        self.send(
+            || self.client.post(url).json(body).timeout(timeout),
+            max_retries,
+        )
+        .await
+    }
+
+    pub async fn get(
+        &self,
+        url: &str,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> reqwest::Result<Response> {
+        self.send(|| self.client.get(url).timeout(timeout), max_retries)
+            .await
+    }
+
+    // Retry only what the peer never took: a connection that couldn't be made, or a 503
+    // from a full message queue, which turns a message away before handling it. A request
+    // that timed out or lost its connection may have been applied already (an /insert, a
+    // transfer), so sending it again is up to the caller.
+    async fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        max_retries: u32,
+    ) -> reqwest::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let result = request().send().await;
+            let retry = match &result {
+                Ok(response) => response.status() == StatusCode::SERVICE_UNAVAILABLE,
+                Err(e) => e.is_connect(),
+            };
+            if !retry || attempt >= max_retries {
+                return result;
+            }
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+        }
+    }
+}
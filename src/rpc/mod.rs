@@ -0,0 +1,5 @@
+use super::*;
+pub mod rpc;
+
+// most nodes a find_successor walk visits before giving up
+pub const MAX_HOPS: usize = 64;
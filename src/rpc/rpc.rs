@@ -0,0 +1,171 @@
+use super::*;
+use crate::hash::ChordId;
+use data::Data;
+use transport::TransportError;
+
+// The calls nodes make to each other to maintain the ring, answered on the same request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcRequest {
+    FindSuccessor { id: ChordId },
+    GetPredecessor,
+    Notify { node_id: String },
+    GetSuccessorList,
+    TransferKeys { from: String, data: Vec<Data> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcResponse {
+    Successor(Successor),
+    Predecessor(Option<String>),
+    Notified { accepted: bool },
+    SuccessorList(Vec<String>),
+    Transferred { keys: usize },
+    Error(RpcError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Successor {
+    // the node responsible for the id
+    Found(String),
+    // the closest node we know of that precedes the id, ask it next
+    Ask(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RpcError {
+    // the node could not be reached or did not answer in time
+    Unreachable(String),
+    // the node answered with something other than what was asked for
+    BadResponse(String),
+    // find_successor kept getting referred on, the ring is inconsistent right now
+    TooManyHops(ChordId),
+    // the node got the request but failed to serve it
+    Failed(String),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Unreachable(e) => write!(f, "unreachable: {}", e),
+            RpcError::BadResponse(e) => write!(f, "bad response: {}", e),
+            RpcError::TooManyHops(id) => {
+                write!(f, "no successor for {} found within {} hops", id, MAX_HOPS)
+            }
+            RpcError::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+// Implemented by Node for the requests it serves, and by Peer to make them of another node
+pub trait ChordRpc {
+    // One step of find_successor, see resolve_successor for the whole walk
+    async fn find_successor(&self, id: ChordId) -> Result<Successor, RpcError>;
+
+    async fn get_predecessor(&self) -> Result<Option<String>, RpcError>;
+
+    // `node_id` might be our predecessor, true if it was taken as one
+    async fn notify(&self, node_id: &str) -> Result<bool, RpcError>;
+
+    async fn get_successor_list(&self) -> Result<Vec<String>, RpcError>;
+
+    // Store the keys `from` hands over, returns how many there were
+    async fn transfer_keys(&self, from: &str, data: Vec<Data>) -> Result<usize, RpcError>;
+}
+
+// Answer a request made to `node`, this is what POST /rpc runs
+pub async fn serve(node: &impl ChordRpc, request: RpcRequest) -> Result<RpcResponse, RpcError> {
+    Ok(match request {
+        RpcRequest::FindSuccessor { id } => RpcResponse::Successor(node.find_successor(id).await?),
+        RpcRequest::GetPredecessor => RpcResponse::Predecessor(node.get_predecessor().await?),
+        RpcRequest::Notify { node_id } => RpcResponse::Notified {
+            accepted: node.notify(&node_id).await?,
+        },
+        RpcRequest::GetSuccessorList => {
+            RpcResponse::SuccessorList(node.get_successor_list().await?)
+        }
+        RpcRequest::TransferKeys { from, data } => RpcResponse::Transferred {
+            keys: node.transfer_keys(&from, data).await?,
+        },
+    })
+}
+
+// Another node, reached through its /rpc endpoint
+#[derive(Debug, Clone)]
+pub struct Peer {
+    addr: String,
+}
+
+impl Peer {
+    pub fn new(addr: &str) -> Self {
+        Peer {
+            addr: addr.to_string(),
+        }
+    }
+
+    async fn call(&self, request: RpcRequest) -> Result<RpcResponse, RpcError> {
+        let client = &peer_client::PEER_CLIENT;
+        let timeout = match request {
+            RpcRequest::TransferKeys { .. } => client.bulk_timeout,
+            _ => client.request_timeout,
+        };
+        match transport::PEER_TRANSPORT
+            .call(&self.addr, &request, timeout)
+            .await
+        {
+            Ok(RpcResponse::Error(e)) => Err(e),
+            Ok(response) => Ok(response),
+            Err(TransportError::Unreachable(e)) => Err(RpcError::Unreachable(e)),
+            Err(e) => Err(RpcError::BadResponse(format!("{}: {}", self.addr, e))),
+        }
+    }
+}
+
+fn unexpected(response: RpcResponse) -> RpcError {
+    RpcError::BadResponse(format!("unexpected {:?}", response))
+}
+
+impl ChordRpc for Peer {
+    async fn find_successor(&self, id: ChordId) -> Result<Successor, RpcError> {
+        match self.call(RpcRequest::FindSuccessor { id }).await? {
+            RpcResponse::Successor(successor) => Ok(successor),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn get_predecessor(&self) -> Result<Option<String>, RpcError> {
+        match self.call(RpcRequest::GetPredecessor).await? {
+            RpcResponse::Predecessor(predecessor) => Ok(predecessor),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn notify(&self, node_id: &str) -> Result<bool, RpcError> {
+        let request = RpcRequest::Notify {
+            node_id: node_id.to_string(),
+        };
+        match self.call(request).await? {
+            RpcResponse::Notified { accepted } => Ok(accepted),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn get_successor_list(&self) -> Result<Vec<String>, RpcError> {
+        match self.call(RpcRequest::GetSuccessorList).await? {
+            RpcResponse::SuccessorList(successors) => Ok(successors),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn transfer_keys(&self, from: &str, data: Vec<Data>) -> Result<usize, RpcError> {
+        let request = RpcRequest::TransferKeys {
+            from: from.to_string(),
+            data,
+        };
+        match self.call(request).await? {
+            RpcResponse::Transferred { keys } => Ok(keys),
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use super::*;
+
+// JSON bodies posted through the shared PeerClient
+#[derive(Debug)]
+pub struct HttpTransport;
+
+impl Transport for HttpTransport {
+    async fn send(
+        &self,
+        peer: &str,
+        message: &Message,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> Result<Message, TransportError> {
+        let envelope = Envelope::new(message);
+        let url = format!("http://{}/msg", peer);
+        let response = send_post_request!(&url, envelope, max_retries, timeout)
+            .map_err(|e| TransportError::Unreachable(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let error = match serde_json::from_str::<Message>(&body) {
+                Ok(Message::ErrorMessage { error }) => error,
+                _ => body,
+            };
+            return Err(TransportError::Rejected(status.as_u16(), error));
+        }
+        response
+            .json::<Message>()
+            .await
+            .map_err(|e| TransportError::Codec(e.to_string()))
+    }
+
+    async fn call(
+        &self,
+        peer: &str,
+        request: &RpcRequest,
+        timeout: Duration,
+    ) -> Result<RpcResponse, TransportError> {
+        let envelope = Envelope::new(request);
+        let url = format!("http://{}/rpc", peer);
+        let max_retries = peer_client::PEER_CLIENT.retry.max_retries;
+        let response = send_post_request!(&url, envelope, max_retries, timeout)
+            .map_err(|e| TransportError::Unreachable(e.to_string()))?;
+        // a request turned down before it was served, like one of a protocol version we
+        // don't take; calls that failed while served come back as RpcResponse::Error
+        let status = response.status();
+        if status.is_client_error() {
+            let body = response.text().await.unwrap_or_default();
+            let error = match serde_json::from_str::<RpcResponse>(&body) {
+                Ok(RpcResponse::Error(e)) => e.to_string(),
+                _ => body,
+            };
+            return Err(TransportError::Rejected(status.as_u16(), error));
+        }
+        response
+            .json::<RpcResponse>()
+            .await
+            .map_err(|e| TransportError::Codec(e.to_string()))
+    }
+}
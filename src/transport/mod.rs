@@ -0,0 +1,116 @@
+use super::*;
+use envelope::{Envelope, PROTOCOL_VERSION};
+use msg::Message;
+use node_state::{host_of, vnode_of};
+use rpc::rpc::{RpcRequest, RpcResponse};
+use std::time::Duration;
+
+pub mod http_transport;
+pub mod tcp_transport;
+
+pub use http_transport::*;
+pub use tcp_transport::*;
+
+// largest frame accepted from a connection
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+// idle connections kept open to each peer
+const MAX_IDLE_PER_PEER: usize = 8;
+
+lazy_static::lazy_static! {
+    // how nodes talk to each other, picked by TRANSPORT for the whole deployment
+    pub static ref PEER_TRANSPORT: PeerTransport = match TRANSPORT.as_str() {
+        "http" => PeerTransport::Http(HttpTransport),
+        "tcp" => PeerTransport::Tcp(TcpTransport::new(
+            std::time::Duration::from_millis(*PEER_CONNECT_TIMEOUT),
+            peer_client::PEER_CLIENT.retry.clone(),
+        )),
+        other => panic!("Unknown TRANSPORT '{}', expected http or tcp", other),
+    };
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    // no connection, or no answer within the timeout of the request
+    Unreachable(String),
+    // the node answered but did not take the message, with the (HTTP) status it gave
+    Rejected(u16, String),
+    // a request or reply that doesn't encode or decode
+    Codec(String),
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Unreachable(e) => write!(f, "{}", e),
+            TransportError::Rejected(status, e) => write!(f, "rejected with {}: {}", status, e),
+            TransportError::Codec(e) => write!(f, "malformed frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+// Carries messages and RPCs from one node to another, retrying connection failures and
+// full message queues (503) up to `max_retries` attempts. A request that went out but got
+// no answer isn't retried, the peer may have handled it. Each attempt gets `timeout` to
+// be answered in.
+pub trait Transport {
+    // Hand a message to a node's message loop, answered with Success, Pong and the like
+    async fn send(
+        &self,
+        peer: &str,
+        message: &Message,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> Result<Message, TransportError>;
+
+    async fn call(
+        &self,
+        peer: &str,
+        request: &RpcRequest,
+        timeout: Duration,
+    ) -> Result<RpcResponse, TransportError>;
+}
+
+#[derive(Debug)]
+pub enum PeerTransport {
+    // JSON over HTTP, the /msg and /rpc endpoints
+    Http(HttpTransport),
+    // bincode frames over persistent TCP connections
+    Tcp(TcpTransport),
+}
+
+impl PeerTransport {
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, PeerTransport::Tcp(_))
+    }
+
+    pub async fn send(
+        &self,
+        peer: &str,
+        message: &Message,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> Result<Message, TransportError> {
+        match self {
+            PeerTransport::Http(transport) => {
+                transport.send(peer, message, max_retries, timeout).await
+            }
+            PeerTransport::Tcp(transport) => {
+                transport.send(peer, message, max_retries, timeout).await
+            }
+        }
+    }
+
+    pub async fn call(
+        &self,
+        peer: &str,
+        request: &RpcRequest,
+        timeout: Duration,
+    ) -> Result<RpcResponse, TransportError> {
+        match self {
+            PeerTransport::Http(transport) => transport.call(peer, request, timeout).await,
+            PeerTransport::Tcp(transport) => transport.call(peer, request, timeout).await,
+        }
+    }
+}
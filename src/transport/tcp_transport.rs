@@ -0,0 +1,397 @@
+use super::*;
+use peer_client::RetryPolicy;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// One request frame on a connection is answered by one reply frame. Requests are sent
+// borrowed (Request<&Message, &RpcRequest>) and received owned.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request<M = Message, R = RpcRequest> {
+    Message { vnode: usize, envelope: Envelope<M> },
+    Rpc { vnode: usize, request: R },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    // the status /msg would have answered with
+    Message { status: u16, message: Message },
+    Rpc(RpcResponse),
+}
+
+// The port a host serves the binary transport on, TCP_PORT_OFFSET above its HTTP port
+pub fn tcp_addr(id: &str) -> String {
+    let host = host_of(id);
+    match host.rsplit_once(':') {
+        Some((ip, port)) => match port.parse::<u16>() {
+            Ok(port) => format!("{}:{}", ip, port.saturating_add(*TCP_PORT_OFFSET)),
+            Err(_) => host.to_string(),
+        },
+        None => host.to_string(),
+    }
+}
+
+// A frame is the length of its bincode payload and the protocol version it is encoded in,
+// both as big-endian u32s, then the payload. Bincode has no field names to skip unknown
+// fields by, so the version is checked before the payload is decoded.
+const HEADER: usize = 8;
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TransportError> {
+    let mut frame = vec![0; HEADER];
+    bincode::serialize_into(&mut frame, value).map_err(|e| TransportError::Codec(e.to_string()))?;
+    let len = frame.len() - HEADER;
+    if len > MAX_FRAME {
+        return Err(TransportError::Codec(format!(
+            "{} bytes is over the {} byte limit",
+            len, MAX_FRAME
+        )));
+    }
+    frame[..4].copy_from_slice(&(len as u32).to_be_bytes());
+    frame[4..HEADER].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    Ok(frame)
+}
+
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, TransportError> {
+    bincode::deserialize(payload).map_err(|e| TransportError::Codec(e.to_string()))
+}
+
+// the protocol version of a frame and its payload
+pub async fn read_frame(stream: &mut TcpStream) -> std::io::Result<(u32, Vec<u8>)> {
+    let len = stream.read_u32().await?;
+    read_rest(stream, len).await
+}
+
+async fn read_rest(stream: &mut TcpStream, len: u32) -> std::io::Result<(u32, Vec<u8>)> {
+    let version = stream.read_u32().await?;
+    let len = len as usize;
+    if len > MAX_FRAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes is over the {} byte limit",
+                len, MAX_FRAME
+            ),
+        ));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).await?;
+    Ok((version, payload))
+}
+
+fn refused(status: u16, error: String) -> Reply {
+    Reply::Message {
+        status,
+        message: Message::ErrorMessage { error },
+    }
+}
+
+fn version_mismatch(version: u32) -> String {
+    format!(
+        "peer speaks protocol version {}, this node speaks {}, nodes on the tcp transport need the same one",
+        version, PROTOCOL_VERSION
+    )
+}
+
+// Accept connections on `listener` and answer every request frame on them with `handle`,
+// one frame at a time per connection until the peer closes it
+pub async fn serve_tcp<F, Fut>(listener: TcpListener, handle: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Reply> + Send,
+{
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // out of file descriptors and the like, give it a moment
+            Err(_) => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let _ = stream.set_nodelay(true);
+            while let Ok((version, payload)) = read_frame(&mut stream).await {
+                // the length prefix keeps the stream in step even when the payload
+                // can't be read, so a frame from another release is turned down like an
+                // incompatible envelope on /msg, and a garbled one as a bad request
+                let reply = if version != PROTOCOL_VERSION {
+                    refused(426, version_mismatch(version))
+                } else {
+                    match decode::<Request>(&payload) {
+                        Ok(request) => handle(request).await,
+                        Err(e) => refused(400, format!("corrupt frame: {}", e)),
+                    }
+                };
+                let Ok(frame) = encode(&reply) else {
+                    return;
+                };
+                if stream.write_all(&frame).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+// Length-prefixed bincode frames over TCP. Connections stay open after a reply and are
+// reused by the next request to the same host, so only the first one pays the handshake.
+#[derive(Debug)]
+pub struct TcpTransport {
+    connect_timeout: Duration,
+    retry: RetryPolicy,
+    idle: Mutex<HashMap<String, Vec<TcpStream>>>,
+}
+
+impl TcpTransport {
+    pub fn new(connect_timeout: Duration, retry: RetryPolicy) -> Self {
+        TcpTransport {
+            connect_timeout,
+            retry,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn request(
+        &self,
+        peer: &str,
+        frame: &[u8],
+        max_retries: u32,
+        timeout: Duration,
+    ) -> Result<Reply, TransportError> {
+        let addr = tcp_addr(peer);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (result, retry) = match self.exchange(&addr, frame, timeout).await {
+                Ok(reply) => {
+                    let busy = matches!(reply, Reply::Message { status: 503, .. });
+                    (Ok(reply), busy)
+                }
+                Err(Failure::Unsent(e)) => (Err(e), true),
+                Err(Failure::Unanswered(e)) => (Err(e), false),
+            };
+            if !retry || attempt >= max_retries {
+                return result;
+            }
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+        }
+    }
+
+    async fn exchange(
+        &self,
+        addr: &str,
+        frame: &[u8],
+        timeout: Duration,
+    ) -> Result<Reply, Failure> {
+        // An idle connection may have been closed by the peer in the meantime,
+        // the request then goes out on a fresh one
+        let pooled = self.idle.lock().await.get_mut(addr).and_then(|c| c.pop());
+        if let Some(stream) = pooled {
+            match self.round_trip(addr, stream, frame, timeout, true).await {
+                Err(Failure::Unsent(_)) => {}
+                result => return result,
+            }
+        }
+
+        let stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| {
+                Failure::Unsent(TransportError::Unreachable(format!(
+                    "connecting to {} timed out",
+                    addr
+                )))
+            })?
+            .map_err(|e| Failure::Unsent(unreachable(addr, e)))?;
+        let _ = stream.set_nodelay(true);
+        self.round_trip(addr, stream, frame, timeout, false).await
+    }
+
+    async fn round_trip(
+        &self,
+        addr: &str,
+        mut stream: TcpStream,
+        frame: &[u8],
+        timeout: Duration,
+        pooled: bool,
+    ) -> Result<Reply, Failure> {
+        let (version, payload) = tokio::time::timeout(timeout, async {
+            // a frame cut short is never read as a request
+            stream
+                .write_all(frame)
+                .await
+                .map_err(|e| Failure::Unsent(unreachable(addr, e)))?;
+            // Peers only close connections when they go down, so a pooled one that is
+            // closed before any reply comes is left over from a peer that restarted and
+            // never read the request
+            let len = stream.read_u32().await.map_err(|e| {
+                if pooled {
+                    Failure::Unsent(unreachable(addr, e))
+                } else {
+                    Failure::Unanswered(unreachable(addr, e))
+                }
+            })?;
+            read_rest(&mut stream, len)
+                .await
+                .map_err(|e| Failure::Unanswered(unreachable(addr, e)))
+        })
+        .await
+        .map_err(|_| {
+            Failure::Unanswered(TransportError::Unreachable(format!(
+                "{} did not answer in time",
+                addr
+            )))
+        })??;
+        if version != PROTOCOL_VERSION {
+            return Err(Failure::Unanswered(TransportError::Rejected(
+                426,
+                version_mismatch(version),
+            )));
+        }
+        let reply = decode(&payload).map_err(Failure::Unanswered)?;
+
+        let mut idle = self.idle.lock().await;
+        let connections = idle.entry(addr.to_string()).or_default();
+        if connections.len() < MAX_IDLE_PER_PEER {
+            connections.push(stream);
+        }
+        Ok(reply)
+    }
+}
+
+// Why a request got no reply
+enum Failure {
+    // it never reached the peer, so it can be sent again
+    Unsent(TransportError),
+    // it went out, the peer may have handled it
+    Unanswered(TransportError),
+}
+
+fn unreachable(addr: &str, e: std::io::Error) -> TransportError {
+    TransportError::Unreachable(format!("{}: {}", addr, e))
+}
+
+impl Transport for TcpTransport {
+    async fn send(
+        &self,
+        peer: &str,
+        message: &Message,
+        max_retries: u32,
+        timeout: Duration,
+    ) -> Result<Message, TransportError> {
+        let frame = encode(&Request::<_, &RpcRequest>::Message {
+            vnode: vnode_of(peer),
+            envelope: Envelope::new(message),
+        })?;
+        match self.request(peer, &frame, max_retries, timeout).await? {
+            Reply::Message { status, message } if (200..300).contains(&status) => Ok(message),
+            Reply::Message {
+                status,
+                message: Message::ErrorMessage { error },
+            } => Err(TransportError::Rejected(status, error)),
+            Reply::Message { status, message } => {
+                Err(TransportError::Rejected(status, format!("{:?}", message)))
+            }
+            Reply::Rpc(_) => Err(TransportError::Codec("rpc reply to a message".to_string())),
+        }
+    }
+
+    async fn call(
+        &self,
+        peer: &str,
+        request: &RpcRequest,
+        timeout: Duration,
+    ) -> Result<RpcResponse, TransportError> {
+        let frame = encode(&Request::<&Message, _>::Rpc {
+            vnode: vnode_of(peer),
+            request,
+        })?;
+        let max_retries = self.retry.max_retries;
+        match self.request(peer, &frame, max_retries, timeout).await? {
+            Reply::Rpc(response) => Ok(response),
+            Reply::Message {
+                status,
+                message: Message::ErrorMessage { error },
+            } => Err(TransportError::Rejected(status, error)),
+            Reply::Message { status, message } => {
+                Err(TransportError::Rejected(status, format!("{:?}", message)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a node that answers every request it can read with 200
+    async fn serve() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_tcp(listener, |_: Request| async {
+            refused(200, "handled".to_string())
+        }));
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    fn request() -> Vec<u8> {
+        encode(&Request::<_, &RpcRequest>::Message {
+            vnode: 0,
+            envelope: Envelope::new(&Message::NodeExists),
+        })
+        .unwrap()
+    }
+
+    async fn exchange(stream: &mut TcpStream, frame: &[u8]) -> (u16, String) {
+        stream.write_all(frame).await.unwrap();
+        let (version, payload) = read_frame(stream).await.unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        match decode(&payload).unwrap() {
+            Reply::Message {
+                status,
+                message: Message::ErrorMessage { error },
+            } => (status, error),
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_carry_the_protocol_version() {
+        let frame = request();
+        assert_eq!(frame[..4], ((frame.len() - HEADER) as u32).to_be_bytes());
+        assert_eq!(frame[4..HEADER], PROTOCOL_VERSION.to_be_bytes());
+
+        let mut stream = serve().await;
+        assert_eq!(exchange(&mut stream, &frame).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_turned_down_before_decoding() {
+        let mut stream = serve().await;
+        let mut frame = request();
+        frame[4..HEADER].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let (status, error) = exchange(&mut stream, &frame).await;
+        assert_eq!(status, 426);
+        assert!(error.contains(&format!("protocol version {}", PROTOCOL_VERSION + 1)));
+
+        // the connection stays in step for the next frame
+        assert_eq!(exchange(&mut stream, &request()).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn garbled_payloads_are_bad_requests() {
+        let mut stream = serve().await;
+        let mut frame = request();
+        frame.truncate(HEADER);
+        frame.extend([0xff; 16]);
+        frame[..4].copy_from_slice(&16u32.to_be_bytes());
+        let (status, error) = exchange(&mut stream, &frame).await;
+        assert_eq!(status, 400);
+        assert!(error.starts_with("corrupt frame"));
+
+        assert_eq!(exchange(&mut stream, &request()).await.0, 200);
+    }
+}